@@ -18,6 +18,17 @@ case "$1" in
 esac
 set -x
 cross build --target arm-unknown-linux-gnueabihf --bin spark --release
-spark rsync av ./target/arm-unknown-linux-gnueabihf/release/spark $target:
-spark ssh $target -- sudo install spark /usr/bin
-spark ssh $target -- spark msg reload
+case "$1" in
+    upload)
+        version="$(sed -n 's/^version = "\(.*\)"$/\1/p' spark/Cargo.toml)"
+        spark backend upload-build \
+            --target arm-unknown-linux-gnueabihf \
+            --version "$version" \
+            ./target/arm-unknown-linux-gnueabihf/release/spark
+        ;;
+    *)
+        spark rsync av ./target/arm-unknown-linux-gnueabihf/release/spark $target:
+        spark ssh $target -- sudo install spark /usr/bin
+        spark ssh $target -- spark msg reload
+        ;;
+esac
@@ -1,18 +1,23 @@
 use std::{
-    collections::{HashMap, hash_map::Entry},
+    collections::{HashMap, HashSet, hash_map::Entry},
     fmt::{self, Display},
     io,
     iter::FromIterator,
     net::{IpAddr, Ipv6Addr},
 };
 
-use chrono::{Duration, Utc};
-use petgraph::{Graph, algo::astar::astar, graph::NodeIndex};
+use chrono::{DateTime, Duration, Utc};
+use petgraph::{
+    Graph,
+    algo::astar::astar,
+    graph::{EdgeReference, NodeIndex},
+    visit::{EdgeFiltered, EdgeRef},
+};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::domain::{
     Hostname, MachineStatus,
-    machine_status::{MachineStatusFull, Port},
+    machine_status::{MachineStatusFull, PeerProbe, Port},
 };
 
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
@@ -49,7 +54,8 @@ impl Display for Node<'_> {
 }
 
 impl MachineStatus {
-    fn share_nat(&self, other: &MachineStatus) -> bool {
+    /// Whether both machines are behind the same NAT, and so can reach each other directly.
+    pub fn share_nat(&self, other: &MachineStatus) -> bool {
         fn ip_eq(a: IpAddr, b: IpAddr) -> bool {
             match (a, b) {
                 (IpAddr::V4(a), IpAddr::V4(b)) => a == b,
@@ -81,18 +87,95 @@ impl Node<'_> {
     }
 }
 
+/// How machines that haven't posted their status in a while are routed through. Stale
+/// machines can always be routed to and from, the policy only applies to using them as hops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Staleness {
+    /// Seconds without a heartbeat after which a machine is stale.
+    #[serde(default = "default_stale_after_secs")]
+    pub after_secs: u64,
+    #[serde(default)]
+    pub policy: StalePolicy,
+}
+
+fn default_stale_after_secs() -> u64 {
+    60 * 60
+}
+
+impl Default for Staleness {
+    fn default() -> Self {
+        Self {
+            after_secs: default_stale_after_secs(),
+            policy: Default::default(),
+        }
+    }
+}
+
+impl Staleness {
+    fn after(&self) -> Duration {
+        Duration::try_seconds(self.after_secs.try_into().unwrap_or(i64::MAX))
+            .unwrap_or(Duration::MAX)
+    }
+
+    /// How long ago the machine was last heard from, if that makes it stale.
+    pub fn stale_for(&self, machine: &MachineStatusFull, now: DateTime<Utc>) -> Option<Duration> {
+        let silent_for = now - machine.last_heartbeat;
+        (silent_for > self.after()).then_some(silent_for)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StalePolicy {
+    /// Route through them like any other machine.
+    Ignore,
+    /// Route through them only when there's no other way.
+    #[default]
+    Penalize,
+    /// Never route through them.
+    Exclude,
+}
+
 #[derive(Debug)]
 pub struct NetGraph<'hostname> {
     graph: Graph<Node<'hostname>, usize>,
+    staleness: Staleness,
+    stale: HashSet<NodeIndex<u32>>,
 }
 
 impl NetGraph<'_> {
     const INTERNET_WEIGHT: usize = 100;
     const INTRANET_WEIGHT: usize = 1;
+    /// Added to every edge leaving a stale hop, when [`StalePolicy::Penalize`]d.
+    const STALE_WEIGHT: usize = 10_000;
+
+    /// The weight of the edge from `from` to `to`, two machines on the same network. Each
+    /// millisecond of round trip `from` measured costs as much as the edge itself, and there's
+    /// no edge if the last probe failed. Machines that haven't probed get the plain weight.
+    fn intranet_weight(from: &MachineStatus, to: &MachineStatus) -> Option<usize> {
+        match from.peers.iter().find(|p| p.hostname == to.hostname) {
+            None => Some(Self::INTRANET_WEIGHT),
+            Some(PeerProbe { rtt: None, .. }) => None,
+            Some(PeerProbe { rtt: Some(rtt), .. }) => Some(
+                usize::try_from(rtt.as_millis())
+                    .unwrap_or(usize::MAX)
+                    .saturating_add(Self::INTRANET_WEIGHT),
+            ),
+        }
+    }
 }
 
 impl<'hostname> FromIterator<&'hostname MachineStatusFull> for NetGraph<'hostname> {
     fn from_iter<T: IntoIterator<Item = &'hostname MachineStatusFull>>(iter: T) -> Self {
+        Self::with_staleness(iter, Staleness::default())
+    }
+}
+
+impl<'hostname> NetGraph<'hostname> {
+    pub fn with_staleness<T>(iter: T, staleness: Staleness) -> Self
+    where
+        T: IntoIterator<Item = &'hostname MachineStatusFull>,
+    {
         let mut graph = Graph::new();
 
         // create the internet
@@ -124,13 +207,33 @@ impl<'hostname> FromIterator<&'hostname MachineStatusFull> for NetGraph<'hostnam
                 })
                 .collect::<Vec<_>>();
 
-            // connect both ways with friends
+            // connect both ways with friends, unless they couldn't reach each other
             for friend in subnet_friends {
-                graph.add_edge(machine_idx, friend, Self::INTRANET_WEIGHT);
-                graph.add_edge(friend, machine_idx, Self::INTRANET_WEIGHT);
+                let friend_status = graph[friend].unwrap_as_machine();
+                let there = Self::intranet_weight(machine, friend_status);
+                let back = Self::intranet_weight(friend_status, machine);
+                if let Some(weight) = there {
+                    graph.add_edge(machine_idx, friend, weight);
+                }
+                if let Some(weight) = back {
+                    graph.add_edge(friend, machine_idx, weight);
+                }
             }
         }
-        Self { graph }
+
+        let now = Utc::now();
+        let stale = graph
+            .node_indices()
+            .filter(|i| match &graph[*i] {
+                Node::Machine(m) => staleness.stale_for(m, now).is_some(),
+                Node::Internet(_) => false,
+            })
+            .collect();
+        Self {
+            graph,
+            staleness,
+            stale,
+        }
     }
 }
 
@@ -142,22 +245,105 @@ pub struct SimpleNode {
 }
 
 impl NetGraph<'_> {
+    /// What an edge costs on a route that starts at `origin`, `None` if it can't be used. The
+    /// [`StalePolicy`] applies to edges leaving stale machines other than the origin, which
+    /// may well be stale itself, like a laptop that was just woken up.
+    fn cost(&self, origin: NodeIndex<u32>, edge: EdgeReference<'_, usize>) -> Option<usize> {
+        let weight = *edge.weight();
+        if edge.source() == origin || !self.stale.contains(&edge.source()) {
+            return Some(weight);
+        }
+        match self.staleness.policy {
+            StalePolicy::Ignore => Some(weight),
+            StalePolicy::Penalize => Some(weight.saturating_add(Self::STALE_WEIGHT)),
+            StalePolicy::Exclude => None,
+        }
+    }
+
     pub fn find_path(&self, from: &Hostname, to: &Hostname) -> Option<Vec<NodeIndex<u32>>> {
         let from = self
             .graph
             .node_indices()
             .find(|i| self.graph[*i].is_host(from))?;
 
+        let graph = EdgeFiltered::from_fn(&self.graph, |e| self.cost(from, e).is_some());
         let (_, nodes) = astar(
-            &self.graph,
+            &graph,
             from,
             |i| self.graph[i].is_host(to),
-            |e| *e.weight(),
+            |e| self.cost(from, e).unwrap_or(usize::MAX),
             |_| 0,
         )?;
         Some(nodes)
     }
 
+    /// Up to `k` paths from `from` to `to` that don't visit any node twice, shortest first. The
+    /// first one is the one [`Self::find_path`] finds.
+    pub fn find_paths(&self, from: &Hostname, to: &Hostname, k: usize) -> Vec<Vec<NodeIndex<u32>>> {
+        // Yen's algorithm: every path after the first branches off one that was already found,
+        // at the node where it stops being the same as the branch it came from
+        let Some(first) = self.find_path(from, to) else {
+            return vec![];
+        };
+        let origin = first[0];
+        let mut found = vec![first];
+        let mut candidates = Vec::<(usize, Vec<NodeIndex<u32>>)>::new();
+        while found.len() < k {
+            let last = found.last().expect("there's always at least one path");
+            for i in 0..last.len() - 1 {
+                let (root, spur) = (&last[..i], last[i]);
+                let taken = found
+                    .iter()
+                    .filter(|p| p.len() > i + 1 && p[..=i] == last[..=i])
+                    .map(|p| (p[i], p[i + 1]))
+                    .collect::<HashSet<_>>();
+                let graph = EdgeFiltered::from_fn(&self.graph, |e| {
+                    !taken.contains(&(e.source(), e.target()))
+                        && !root.contains(&e.source())
+                        && !root.contains(&e.target())
+                        && self.cost(origin, e).is_some()
+                });
+                let Some((_, spur_path)) = astar(
+                    &graph,
+                    spur,
+                    |i| self.graph[i].is_host(to),
+                    |e| self.cost(origin, e).unwrap_or(usize::MAX),
+                    |_| 0,
+                ) else {
+                    continue;
+                };
+                let path = root.iter().copied().chain(spur_path).collect::<Vec<_>>();
+                if !found.contains(&path) && !candidates.iter().any(|(_, p)| *p == path) {
+                    candidates.push((self.path_cost(&path), path));
+                }
+            }
+            let Some(best) = candidates
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (cost, _))| *cost)
+                .map(|(i, _)| i)
+            else {
+                break;
+            };
+            found.push(candidates.swap_remove(best).1);
+        }
+        found.truncate(k);
+        found
+    }
+
+    /// What a path costs, starting from its first node.
+    fn path_cost(&self, path: &[NodeIndex<u32>]) -> usize {
+        path.windows(2)
+            .map(|hop| {
+                self.graph
+                    .edges_connecting(hop[0], hop[1])
+                    .filter_map(|e| self.cost(path[0], e))
+                    .min()
+                    .unwrap_or(usize::MAX)
+            })
+            .fold(0, usize::saturating_add)
+    }
+
     pub fn path_to_ips(&self, nodes: &[NodeIndex<u32>]) -> Option<Vec<SimpleNode>> {
         let mut i = nodes.iter();
         let mut v = vec![];
@@ -231,11 +417,13 @@ impl NetGraph<'_> {
         )
         .await?;
 
-        let (today, one_hour_ago) = {
+        let (today, stale_before) = {
             let today = Utc::now();
-            let one_hour_ago = today - Duration::try_hours(1).unwrap();
+            let stale_before = today
+                .checked_sub_signed(self.staleness.after())
+                .unwrap_or(DateTime::<Utc>::MIN_UTC);
 
-            (today.timestamp_millis(), one_hour_ago.timestamp_millis())
+            (today.timestamp_millis(), stale_before.timestamp_millis())
         };
         for (ip, nodes) in by_subnet.into_iter() {
             let subgraph_label = ip.to_string().replace(['.', ':'], "_");
@@ -243,12 +431,12 @@ impl NetGraph<'_> {
                 .await?;
             for (i, n) in nodes {
                 let hb = n.last_heartbeat.timestamp_millis();
-                let color = if hb < one_hour_ago {
+                let color = if hb < stale_before {
                     tracing::info!("node: {} @ {:?} :: {}", n.hostname, n.last_heartbeat, 1);
                     tracing::debug!("node: {:#?} :: {}", n, 1);
                     String::from(" style=filled fillcolor=1")
                 } else {
-                    let color = 1 + ((7 * (hb - one_hour_ago)) / (today - one_hour_ago));
+                    let color = 1 + ((7 * (hb - stale_before)) / (today - stale_before).max(1));
                     tracing::info!("node: {} @ {:?} :: {}", n.hostname, n.last_heartbeat, color);
                     tracing::debug!("node: {:#?} :: {}", n, color);
                     format!(" style=filled fillcolor={color}")
@@ -258,7 +446,7 @@ impl NetGraph<'_> {
                         "        {} [ label = \"{}{}\" {color} ]\n",
                         i.index(),
                         Node::Machine(n),
-                        if hb < one_hour_ago {
+                        if hb < stale_before {
                             format!("\n{}", n.last_heartbeat)
                         } else {
                             String::new()
@@ -335,6 +523,7 @@ mod test {
                 external_ip: IP().fake(),
                 ssh: None,
                 default_user: None,
+                peers: vec![],
             },
             last_heartbeat: Utc::now(),
         }
@@ -463,4 +652,173 @@ mod test {
         let path = NetGraph::from_iter(&v).find_path(&v[2].hostname, &v[0].hostname);
         assert_eq!(path, None)
     }
+
+    fn probe(to: &MachineStatusFull, rtt: Option<u64>) -> PeerProbe {
+        PeerProbe {
+            hostname: to.hostname.clone(),
+            rtt: rtt.map(std::time::Duration::from_millis),
+        }
+    }
+
+    #[test]
+    fn failed_probes_drop_the_hop() {
+        let host1 = mock_machine_status();
+        let external_ip = IP().fake();
+        let host3 = mock_machine_status().also(|m| m.external_ip = external_ip);
+        let host2 = mock_machine_status()
+            .also(|m| m.external_ip = external_ip)
+            .also(|m| m.ssh = Some(222))
+            .also(|m| m.peers = vec![probe(&host3, None)]);
+        let v = [host1, host2, host3];
+        let path = NetGraph::from_iter(&v).find_path(&v[0].hostname, &v[2].hostname);
+        assert_eq!(path, None)
+    }
+
+    #[test]
+    fn the_fastest_hop_is_picked() {
+        let host1 = mock_machine_status();
+        let external_ip = IP().fake();
+        let target = mock_machine_status().also(|m| m.external_ip = external_ip);
+        let forwarded = |port, rtt| {
+            mock_machine_status()
+                .also(|m| m.external_ip = external_ip)
+                .also(|m| m.ssh = Some(port))
+                .also(|m| m.peers = vec![probe(&target, Some(rtt))])
+        };
+        let v = [host1, forwarded(2222, 50), forwarded(2223, 2), target];
+        let netgraph = NetGraph::from_iter(&v);
+        let path =
+            netgraph.path_to_ips(&netgraph.find_path(&v[0].hostname, &v[3].hostname).unwrap());
+        assert_eq!(path.unwrap()[1].port, 2223);
+    }
+
+    #[test]
+    fn alternative_paths_come_after_the_shortest() {
+        let host1 = mock_machine_status();
+        let external_ip = IP().fake();
+        let target = mock_machine_status().also(|m| m.external_ip = external_ip);
+        let forwarded = |port| {
+            mock_machine_status()
+                .also(|m| m.external_ip = external_ip)
+                .also(|m| m.ssh = Some(port))
+        };
+        let v = [host1, forwarded(2222), forwarded(2223), target];
+        let netgraph = NetGraph::from_iter(&v);
+        let paths = netgraph.find_paths(&v[0].hostname, &v[3].hostname, 5);
+        assert_eq!(
+            paths.first(),
+            netgraph.find_path(&v[0].hostname, &v[3].hostname).as_ref()
+        );
+        assert!(paths.len() > 2, "{paths:?}");
+        let costs = paths
+            .iter()
+            .map(|p| netgraph.path_cost(p))
+            .collect::<Vec<_>>();
+        assert!(costs.is_sorted(), "{costs:?}");
+        let first_hops = paths
+            .iter()
+            .map(|p| netgraph.path_to_ips(p).unwrap()[1].port)
+            .collect::<HashSet<_>>();
+        assert_eq!(first_hops, HashSet::from([2222, 2223]));
+        for path in &paths {
+            assert_eq!(path.iter().collect::<HashSet<_>>().len(), path.len());
+        }
+    }
+
+    #[test]
+    fn there_are_no_alternatives_to_no_path() {
+        let v = [mock_machine_status(), mock_machine_status()];
+        let netgraph = NetGraph::from_iter(&v);
+        assert!(
+            netgraph
+                .find_paths(&v[0].hostname, &v[1].hostname, 3)
+                .is_empty()
+        );
+    }
+
+    /// host1 reaches the target through one of two forwarded machines, the one it'd rather use
+    /// hasn't been heard from in two days.
+    fn stale_hop() -> [MachineStatusFull; 4] {
+        let host1 = mock_machine_status();
+        let external_ip = IP().fake();
+        let target = mock_machine_status().also(|m| m.external_ip = external_ip);
+        let forwarded = |port, rtt| {
+            mock_machine_status()
+                .also(|m| m.external_ip = external_ip)
+                .also(|m| m.ssh = Some(port))
+                .also(|m| m.peers = vec![probe(&target, Some(rtt))])
+        };
+        let stale = forwarded(2222, 2)
+            .also(|m| m.last_heartbeat = Utc::now() - Duration::try_days(2).unwrap());
+        [host1, stale, forwarded(2223, 50), target]
+    }
+
+    fn first_hop_port(netgraph: &NetGraph, from: &Hostname, to: &Hostname) -> Option<Port> {
+        let path = netgraph.find_path(from, to)?;
+        Some(netgraph.path_to_ips(&path)?[1].port)
+    }
+
+    #[test]
+    fn stale_hops_are_avoided() {
+        let v = stale_hop();
+        let (from, to) = (&v[0].hostname, &v[3].hostname);
+        for (policy, port) in [
+            (StalePolicy::Ignore, 2222),
+            (StalePolicy::Penalize, 2223),
+            (StalePolicy::Exclude, 2223),
+        ] {
+            let staleness = Staleness {
+                policy,
+                ..Default::default()
+            };
+            let netgraph = NetGraph::with_staleness(&v, staleness);
+            assert_eq!(
+                first_hop_port(&netgraph, from, to),
+                Some(port),
+                "{policy:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn stale_hops_are_used_as_a_last_resort_unless_excluded() {
+        let [host1, stale, _, target] = stale_hop();
+        let v = [host1, stale, target];
+        let (from, to) = (&v[0].hostname, &v[2].hostname);
+        let penalized = NetGraph::with_staleness(&v, Staleness::default());
+        assert_eq!(first_hop_port(&penalized, from, to), Some(2222));
+        let excluded = NetGraph::with_staleness(
+            &v,
+            Staleness {
+                policy: StalePolicy::Exclude,
+                ..Default::default()
+            },
+        );
+        assert_eq!(excluded.find_path(from, to), None);
+        // it can still be reached itself
+        assert!(excluded.find_path(from, &v[1].hostname).is_some());
+    }
+
+    #[test]
+    fn stale_origins_can_still_route() {
+        let [_, stale, fresh, target] = stale_hop();
+        let v = [stale.also(|m| m.ssh = None), fresh, target];
+        let (from, to) = (&v[0].hostname, &v[2].hostname);
+        for policy in [StalePolicy::Penalize, StalePolicy::Exclude] {
+            let netgraph = NetGraph::with_staleness(
+                &v,
+                Staleness {
+                    policy,
+                    ..Default::default()
+                },
+            );
+            let path = netgraph
+                .find_path(from, to)
+                .expect("the origin's edges were dropped");
+            // straight to the target, at the cost of the round trip it measured
+            assert_eq!(path.len(), 2, "{policy:?}");
+            assert_eq!(netgraph.path_cost(&path), 3, "{policy:?}");
+            assert_eq!(netgraph.find_paths(from, to, 3)[0], path, "{policy:?}");
+        }
+    }
 }
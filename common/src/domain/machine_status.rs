@@ -6,6 +6,7 @@ use super::MacAddr;
 use std::net::IpAddr;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::time::Duration;
 
 pub type Port = u16;
 
@@ -18,6 +19,9 @@ pub struct MachineStatus {
     pub external_ip: IpAddr,
     #[serde(default)]
     pub default_user: Option<String>,
+    /// How well the machines behind the same NAT could be reached from this one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<PeerProbe>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -62,3 +66,11 @@ pub struct IpConnection {
     #[serde(default)]
     pub gateway_mac: Option<MacAddr>,
 }
+
+/// The outcome of connecting to the ssh port of another machine on the same network.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PeerProbe {
+    pub hostname: Hostname,
+    /// How long the connection took to establish, `None` if it couldn't be.
+    pub rtt: Option<Duration>,
+}
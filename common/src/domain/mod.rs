@@ -4,6 +4,7 @@ pub mod machine_status;
 pub mod music_session;
 #[cfg(feature = "playlist")]
 pub mod playlist;
+pub mod spark_build;
 
 pub use hostname::Hostname;
 pub use mac::MacAddr;
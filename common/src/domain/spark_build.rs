@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+
+/// A spark binary published on the backend, for sparks running on `target` to update to.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SparkBuild {
+    /// The target triple the binary was built for, like `arm-unknown-linux-gnueabihf`.
+    pub target: String,
+    pub version: String,
+    /// md5 of the binary, hex encoded.
+    pub checksum: String,
+    pub size: u64,
+}
+
+/// Whether `target` looks like a target triple, which also makes it safe to use as a file name.
+pub fn is_valid_target(target: &str) -> bool {
+    !target.is_empty()
+        && target
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Whether `version` looks like a crate version, which also makes it safe to use as a file name.
+pub fn is_valid_version(version: &str) -> bool {
+    version.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && version
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'+' | b'_'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn targets_cant_escape_the_builds_directory() {
+        assert!(is_valid_target("arm-unknown-linux-gnueabihf"));
+        assert!(is_valid_target("x86_64-unknown-linux-gnu"));
+        assert!(!is_valid_target(""));
+        assert!(!is_valid_target("../../etc/passwd"));
+        assert!(!is_valid_target("x86_64.json"));
+    }
+
+    #[test]
+    fn versions_cant_escape_the_builds_directory() {
+        assert!(is_valid_version("0.5.21"));
+        assert!(is_valid_version("1.0.0-rc.1+build.5"));
+        assert!(!is_valid_version(""));
+        assert!(!is_valid_version(".."));
+        assert!(!is_valid_version(".hidden"));
+        assert!(!is_valid_version("0.5/../../etc"));
+    }
+}
@@ -1,2 +1,6 @@
 pub const NS: &str = "/spark-protocol";
 pub const COMMAND: &str = "command";
+pub const HANDSHAKE: &str = "handshake";
+pub const SUBSCRIBE: &str = "subscribe";
+pub const UNSUBSCRIBE: &str = "unsubscribe";
+pub const EVENT: &str = "event";
@@ -23,6 +23,14 @@ make_metric!(
             spark_protocol::music::MusicCmdKind::Current => "Current",
             spark_protocol::music::MusicCmdKind::Queue { .. } => "Queue",
             spark_protocol::music::MusicCmdKind::Now { .. } => "Now",
+            spark_protocol::music::MusicCmdKind::Seek { .. } => "Seek",
+            spark_protocol::music::MusicCmdKind::Shuffle => "Shuffle",
+            spark_protocol::music::MusicCmdKind::Loop => "Loop",
+            spark_protocol::music::MusicCmdKind::Remove { .. } => "Remove",
+            spark_protocol::music::MusicCmdKind::Move { .. } => "Move",
+            spark_protocol::music::MusicCmdKind::Clear => "Clear",
+            spark_protocol::music::MusicCmdKind::Jump { .. } => "Jump",
+            spark_protocol::music::MusicCmdKind::ListPlayers => "ListPlayers",
         }
     }
 );
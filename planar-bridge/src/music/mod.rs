@@ -1,4 +1,5 @@
 mod request_coalescing;
+mod subscription;
 
 use std::{sync::Arc, time::Duration};
 
@@ -14,7 +15,7 @@ use mappable_rc::Marc;
 use mlib::{playlist::PartialSearchResult, queue::Current};
 use serde::Deserialize;
 use spark_protocol::{
-    SuccessfulResponse,
+    ErrorResponse, SuccessfulResponse,
     music::{MusicCmd, MusicCmdKind, Response},
 };
 use uuid::Uuid;
@@ -35,6 +36,7 @@ pub fn routes() -> Router<RouterState> {
         .route("/now", get(now))
         .route("/search", post(search))
         .route("/queue", post(queue))
+        .route("/players", get(list_players))
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -45,6 +47,8 @@ enum Error {
     UnexpectedBackendResponse(String),
     #[error("player not found")]
     PlayerOrSessionNotFound,
+    #[error("player did not respond in time")]
+    PlayerTimeout,
 }
 
 impl<T> From<T> for Error
@@ -61,11 +65,22 @@ impl Error {
         match self {
             Self::UnexpectedBackendResponse(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::PlayerOrSessionNotFound => StatusCode::NOT_FOUND,
+            Self::PlayerTimeout => StatusCode::GATEWAY_TIMEOUT,
             Self::Common(e) => e.status_code(),
         }
     }
 }
 
+impl From<ErrorResponse> for Error {
+    fn from(e: ErrorResponse) -> Self {
+        match e {
+            ErrorResponse::NoPlayer { .. } => Self::PlayerOrSessionNotFound,
+            ErrorResponse::PlayerTimeout { .. } => Self::PlayerTimeout,
+            e => Self::UnexpectedBackendResponse(format!("{e:?}")),
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
         (self.status_code(), self.to_string()).into_response()
@@ -79,14 +94,18 @@ async fn request_from_backend(
 ) -> Result<spark_protocol::music::Response, Error> {
     metrics::music_backend_request(&cmd).inc();
     let request = match target {
-        Target::Host { hostname, auth } => client
+        Target::Host {
+            hostname,
+            auth,
+            player,
+        } => client
             .post(&format!("/persistent-connections/ws/send/{hostname}"))
             .expect("url should always parse")
             .bearer_auth(auth)
             .json(&spark_protocol::Command::Music(
                 spark_protocol::music::MusicCmd {
                     command: cmd,
-                    index: None,
+                    index: *player,
                     username: None,
                 },
             )),
@@ -110,14 +129,21 @@ async fn request_from_backend(
         Ok(r) => Err(Error::UnexpectedBackendResponse(format!(
             "not a music response: {r:?}"
         ))),
-        Err(e) => Err(Error::UnexpectedBackendResponse(format!("{e:?}"))),
+        Err(e) => Err(e.into()),
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum Target {
-    Host { hostname: Hostname, auth: Uuid },
-    Session { session: MusicSession },
+    Host {
+        hostname: Hostname,
+        auth: Uuid,
+        /// The player to control, the host's current one if `None`.
+        player: Option<usize>,
+    },
+    Session {
+        session: MusicSession,
+    },
 }
 
 impl<S> FromRequestParts<S> for Target
@@ -130,11 +156,12 @@ where
         parts: &mut http::request::Parts,
         state: &S,
     ) -> Result<Self, Self::Rejection> {
+        // not an untagged enum because those can't parse numbers out of query strings
         #[derive(Deserialize)]
-        #[serde(untagged)]
-        enum Target {
-            Host { hostname: Hostname },
-            Session { session: MusicSession },
+        struct Target {
+            hostname: Option<Hostname>,
+            session: Option<MusicSession>,
+            player: Option<usize>,
         }
 
         let Query(target) = Query::<Target>::from_request_parts(parts, state)
@@ -146,12 +173,32 @@ where
         };
 
         match target {
-            Target::Host { hostname } => header_map
+            Target {
+                hostname: Some(hostname),
+                player,
+                ..
+            } => header_map
                 .get(header::AUTHORIZATION)
                 .and_then(|a| a.to_str().ok()?.parse().ok())
-                .map(|auth| Self::Host { hostname, auth })
+                .map(|auth| Self::Host {
+                    hostname,
+                    auth,
+                    player,
+                })
                 .ok_or(crate::Error::Unauthorized.into()),
-            Target::Session { session } => Ok(Self::Session { session }),
+            // sessions only get to control the default player
+            Target {
+                session: Some(session),
+                player: None,
+                ..
+            } => Ok(Self::Session { session }),
+            Target {
+                session: Some(_), ..
+            } => Err(crate::Error::BadRequest("sessions can't pick a player".into()).into()),
+            Target { .. } => Err(crate::Error::BadRequest(
+                "either a hostname or a session is required".into(),
+            )
+            .into()),
         }
     }
 }
@@ -159,7 +206,16 @@ where
 impl Target {
     fn to_query_string(&self) -> String {
         match self {
-            Self::Host { hostname, .. } => format!("hostname={hostname}"),
+            Self::Host {
+                hostname,
+                player: None,
+                ..
+            } => format!("hostname={hostname}"),
+            Self::Host {
+                hostname,
+                player: Some(player),
+                ..
+            } => format!("hostname={hostname}&player={player}"),
             Self::Session { session } => format!("session={session}"),
         }
     }
@@ -229,12 +285,19 @@ async fn ctl(
             .into_response(),
         Response::Title { title } => (StatusCode::OK, title).into_response(),
         Response::Volume { volume } => (StatusCode::OK, volume.to_string()).into_response(),
-        Response::Current { .. } | Response::QueueSummary { .. } => (
+        Response::Current { .. }
+        | Response::QueueSummary { .. }
+        | Response::Position { .. }
+        | Response::Shuffle { .. }
+        | Response::Loop { .. }
+        | Response::Removed { .. }
+        | Response::Moved { .. }
+        | Response::Cleared => (
             StatusCode::OK,
             AppendHeaders([("hx-trigger", "new-current")]),
         )
             .into_response(),
-        Response::Now { .. } => StatusCode::BAD_REQUEST.into_response(),
+        Response::Now { .. } | Response::Players { .. } => StatusCode::BAD_REQUEST.into_response(),
     };
 
     Ok(res)
@@ -334,6 +397,9 @@ async fn get_current(
     state: State<RouterState>,
     target: Target,
 ) -> Result<Marc<Current>, SharedError> {
+    if let Some(current) = subscription::current(&state.client, &target).await {
+        return Ok(Marc::new(current));
+    }
     cache::get_or_init(
         &target.to_query_string(),
         || async {
@@ -392,3 +458,13 @@ async fn queue(
         .render()?,
     ))
 }
+
+async fn list_players(
+    state: State<RouterState>,
+    target: Target,
+) -> Result<impl IntoResponse, Error> {
+    match request_from_backend(&state.client, &target, MusicCmdKind::ListPlayers).await? {
+        Response::Players { players } => Ok(Json(players)),
+        response => Err(Error::UnexpectedBackendResponse(format!("{response:?}"))),
+    }
+}
@@ -0,0 +1,227 @@
+//! Keeps the current song of every target that's being looked at up to date by subscribing to its
+//! player's events, instead of asking the remote spark for it every time a page refreshes.
+
+use std::{collections::HashMap, io, sync::OnceLock, time::Duration};
+
+use common::net::ReadJsonLinesExt;
+use futures::TryStreamExt;
+use http::StatusCode;
+use mlib::queue::Current;
+use spark_protocol::{
+    SuccessfulResponse,
+    music::{MusicCmdKind, Response, Subscription},
+};
+use tokio::{
+    io::{AsyncRead, BufReader},
+    sync::{Mutex, watch},
+    time::{Instant, timeout},
+};
+use tokio_util::io::StreamReader;
+
+use crate::Backend;
+
+use super::{Error, Target};
+
+/// How long to go without events before fetching the whole state again, to correct any drift.
+const RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+/// Stop following targets nobody asked about for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait before subscribing again after a subscription failed, for example because the
+/// remote spark is too old to support them.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+struct Snapshot {
+    current: Current,
+    taken_at: Instant,
+}
+
+impl Snapshot {
+    fn new(current: Current) -> Self {
+        Self {
+            current,
+            taken_at: Instant::now(),
+        }
+    }
+
+    /// The snapshot with the playback position moved forward to now.
+    fn now(&self) -> Current {
+        let mut current = self.current.clone();
+        if current.playing
+            && let Some(playback_time) = &mut current.playback_time
+        {
+            *playback_time = (*playback_time + self.taken_at.elapsed()).min(current.duration);
+            if !current.duration.is_zero() {
+                current.progress =
+                    Some(playback_time.as_secs_f64() / current.duration.as_secs_f64() * 100.);
+            }
+        }
+        current
+    }
+}
+
+struct Follower {
+    snapshot: watch::Receiver<Option<Snapshot>>,
+    started_at: Instant,
+    last_used: Instant,
+}
+
+#[derive(Default)]
+struct Followers {
+    following: Mutex<HashMap<Target, Follower>>,
+}
+
+static FOLLOWERS: OnceLock<Followers> = OnceLock::new();
+
+/// The current song of `target`, as kept up to date by its subscription.
+///
+/// Returns `None` while there is no live subscription, in which case the caller should ask the
+/// backend directly. Calling this starts one if needed.
+pub async fn current(client: &Backend, target: &Target) -> Option<Current> {
+    let followers = FOLLOWERS.get_or_init(Default::default);
+    let mut following = followers.following.lock().await;
+    let now = Instant::now();
+    if let Some(follower) = following.get_mut(target) {
+        follower.last_used = now;
+        // an error means the subscription ended
+        if follower.snapshot.has_changed().is_ok() {
+            return follower.snapshot.borrow().as_ref().map(Snapshot::now);
+        }
+        if follower.started_at.elapsed() < RETRY_INTERVAL {
+            return None;
+        }
+    }
+    let (tx, rx) = watch::channel(None);
+    following.insert(
+        target.clone(),
+        Follower {
+            snapshot: rx,
+            started_at: now,
+            last_used: now,
+        },
+    );
+    tokio::spawn(follow(client.clone(), target.clone(), tx));
+    None
+}
+
+#[tracing::instrument(skip(client, snapshot))]
+async fn follow(client: Backend, target: Target, snapshot: watch::Sender<Option<Snapshot>>) {
+    match follow_events(&client, &target, &snapshot).await {
+        Ok(()) => tracing::info!("stopped following player"),
+        Err(e) => tracing::warn!(error = %e, "subscription to player failed"),
+    }
+}
+
+async fn follow_events(
+    client: &Backend,
+    target: &Target,
+    snapshot: &watch::Sender<Option<Snapshot>>,
+) -> Result<(), Error> {
+    let mut events = subscribe(client, target).await?;
+    snapshot.send_replace(Some(fetch_current(client, target).await?));
+    loop {
+        let event = match timeout(RESYNC_INTERVAL, events.recv::<spark_protocol::Response>()).await
+        {
+            Ok(event) => event.map_err(io::Error::from)?,
+            Err(_elapsed) => {
+                if is_idle(target).await {
+                    return Ok(());
+                }
+                snapshot.send_replace(Some(fetch_current(client, target).await?));
+                continue;
+            }
+        };
+        match event {
+            None => return Ok(()),
+            Some(Ok(SuccessfulResponse::MusicResponse(event))) => match event {
+                // a new song means a new duration, chapters, etc
+                Response::Title { .. } => {
+                    snapshot.send_replace(Some(fetch_current(client, target).await?));
+                }
+                Response::PlayState { paused } => snapshot.send_modify(|s| {
+                    if let Some(s) = s {
+                        *s = Snapshot::new(Current {
+                            playing: !paused,
+                            ..s.now()
+                        });
+                    }
+                }),
+                Response::Volume { volume } => snapshot.send_modify(|s| {
+                    if let Some(s) = s {
+                        s.current.volume = volume;
+                    }
+                }),
+                event => tracing::debug!(?event, "ignoring unexpected event"),
+            },
+            Some(Ok(r)) => {
+                return Err(Error::UnexpectedBackendResponse(format!(
+                    "not a music response: {r:?}"
+                )));
+            }
+            Some(Err(e)) => return Err(e.into()),
+        }
+        if is_idle(target).await {
+            return Ok(());
+        }
+    }
+}
+
+/// Checks whether anyone still cares about `target`, forgetting about it if not.
+async fn is_idle(target: &Target) -> bool {
+    let followers = FOLLOWERS.get_or_init(Default::default);
+    let mut following = followers.following.lock().await;
+    let idle = following
+        .get(target)
+        .is_none_or(|f| f.last_used.elapsed() > IDLE_TIMEOUT);
+    if idle {
+        following.remove(target);
+    }
+    idle
+}
+
+async fn fetch_current(client: &Backend, target: &Target) -> Result<Snapshot, Error> {
+    match super::request_from_backend(client, target, MusicCmdKind::Current).await? {
+        Response::Current { current } => Ok(Snapshot::new(current)),
+        response => Err(Error::UnexpectedBackendResponse(format!("{response:?}"))),
+    }
+}
+
+async fn subscribe(
+    client: &Backend,
+    target: &Target,
+) -> Result<BufReader<impl AsyncRead + Unpin + Send>, Error> {
+    let request = match target {
+        Target::Host {
+            hostname,
+            auth,
+            player,
+        } => client
+            .post(&format!("/persistent-connections/ws/subscribe/{hostname}"))
+            .expect("url should always parse")
+            .bearer_auth(auth)
+            .json(&Subscription {
+                events: vec![],
+                index: *player,
+                username: None,
+            }),
+        Target::Session { session } => client
+            .post(&format!("/music/ws/{session}/subscribe"))
+            .expect("url should always parse")
+            .json(&Subscription {
+                events: vec![],
+                index: None,
+                username: None,
+            }),
+    };
+    let response = request.send().await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Err(Error::PlayerOrSessionNotFound);
+    }
+
+    let body = response
+        .error_for_status()?
+        .bytes_stream()
+        .map_err(io::Error::other);
+    Ok(BufReader::new(StreamReader::new(Box::pin(body))))
+}
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX queued_commands_hostnames;
+
+DROP TABLE queued_commands;
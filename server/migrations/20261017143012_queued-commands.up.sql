@@ -0,0 +1,11 @@
+-- Add up migration script here
+CREATE TABLE queued_commands (
+    id BIGSERIAL PRIMARY KEY,
+    hostname VARCHAR(253) NOT NULL,
+    command TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    sent_at TIMESTAMP NULL,
+    response TEXT NULL
+);
+
+CREATE INDEX queued_commands_hostnames ON queued_commands (hostname);
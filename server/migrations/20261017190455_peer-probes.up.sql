@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE peer_probe (
+    hostname VARCHAR(253) NOT NULL,
+    peer VARCHAR(253) NOT NULL,
+    rtt_micros BIGINT NULL,
+
+    PRIMARY KEY (hostname, peer),
+    FOREIGN KEY (hostname) REFERENCES machine_status(hostname)
+);
@@ -1,5 +1,15 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
+use spark_protocol::relay::SubscriptionId;
+use tokio::sync::mpsc;
+
+pub mod queue;
 pub mod ws;
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, PartialOrd, Ord, Hash)]
@@ -12,3 +22,34 @@ impl Generation {
         Self(GENERATION.fetch_add(1, Ordering::SeqCst))
     }
 }
+
+/// The tags a spark was configured with, used to pick the targets of broadcasts.
+#[derive(Debug, Clone, Default)]
+pub struct Tags(pub Arc<BTreeSet<String>>);
+
+/// The subscriptions being relayed through a socket, keyed by the id the spark tags its events
+/// with.
+#[derive(Debug, Clone, Default)]
+pub struct Subscriptions(
+    Arc<Mutex<HashMap<SubscriptionId, mpsc::Sender<spark_protocol::Response>>>>,
+);
+
+impl Subscriptions {
+    pub fn next_id() -> SubscriptionId {
+        static ID: AtomicU64 = AtomicU64::new(0);
+
+        ID.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub fn insert(&self, id: SubscriptionId, tx: mpsc::Sender<spark_protocol::Response>) {
+        self.0.lock().unwrap().insert(id, tx);
+    }
+
+    pub fn get(&self, id: SubscriptionId) -> Option<mpsc::Sender<spark_protocol::Response>> {
+        self.0.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn remove(&self, id: SubscriptionId) {
+        self.0.lock().unwrap().remove(&id);
+    }
+}
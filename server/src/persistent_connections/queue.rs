@@ -0,0 +1,147 @@
+//! Commands sent to sparks that weren't connected, kept until they connect or the command
+//! expires.
+//!
+//! A queued command is claimed by stamping its `sent_at` when its spark connects, and released
+//! again if the socket closes before the command could be emitted, so it's never sent twice.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use common::domain::Hostname;
+use socketioxide::extract::SocketRef;
+use spark_protocol::{
+    Command,
+    relay::{Delivery, Queued, QueuedId},
+};
+use sqlx::PgPool;
+
+use crate::routes::persistent_connections::{RelayFailure, SEND_TIMEOUT, relay_command};
+
+/// Longest a command may wait for its spark.
+pub const MAX_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How long the outcome of a command is kept around after it expired, for callers that poll
+/// late.
+const RETENTION: chrono::TimeDelta = chrono::TimeDelta::days(1);
+
+pub async fn enqueue(
+    db: &PgPool,
+    hostname: &Hostname,
+    command: &Command,
+    ttl: Duration,
+) -> sqlx::Result<Queued> {
+    let ttl = ttl.min(MAX_TTL);
+    let now = Utc::now().naive_utc();
+    sqlx::query!(
+        r#"DELETE FROM queued_commands WHERE expires_at < $1"#,
+        now - RETENTION
+    )
+    .execute(db)
+    .await?;
+
+    let command = serde_json::to_string(command).expect("commands always serialize");
+    let id = sqlx::query!(
+        r#"INSERT INTO queued_commands (hostname, command, expires_at)
+        VALUES ($1, $2, $3)
+        RETURNING id"#,
+        hostname.as_ref(),
+        command,
+        now + chrono::Duration::from_std(ttl).expect("ttl is capped"),
+    )
+    .fetch_one(db)
+    .await?
+    .id;
+    tracing::info!(id, %hostname, ?ttl, "command queued");
+    Ok(Queued {
+        id,
+        expires_in: ttl,
+    })
+}
+
+pub async fn status(db: &PgPool, id: QueuedId) -> sqlx::Result<Option<Delivery>> {
+    let row = sqlx::query!(
+        r#"SELECT expires_at, sent_at, response FROM queued_commands WHERE id = $1"#,
+        id
+    )
+    .fetch_optional(db)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let delivery = match (row.sent_at, row.response) {
+        (_, Some(response)) => Delivery::Delivered {
+            response: serde_json::from_str(&response).unwrap_or_else(|e| {
+                Box::new(Err(spark_protocol::ErrorResponse::RelayError(format!(
+                    "stored response is corrupt: {e}"
+                ))))
+            }),
+        },
+        (Some(_), None) => Delivery::Sent,
+        (None, None) => match (row.expires_at - Utc::now().naive_utc()).to_std() {
+            Ok(expires_in) => Delivery::Pending { expires_in },
+            Err(_) => Delivery::Expired,
+        },
+    };
+    Ok(Some(delivery))
+}
+
+/// A command claimed for the spark that just connected.
+struct Claimed {
+    id: QueuedId,
+    command: String,
+}
+
+/// Sends every command queued for `hostname` to the spark that just connected through `socket`,
+/// oldest first, and stores what it replied.
+#[tracing::instrument(skip(db, socket))]
+pub async fn replay(db: &PgPool, socket: SocketRef, hostname: &Hostname) -> sqlx::Result<()> {
+    let claimed_at = Utc::now().naive_utc();
+    let mut claimed = sqlx::query_as!(
+        Claimed,
+        r#"UPDATE queued_commands SET sent_at = $2
+        WHERE hostname = $1 AND sent_at IS NULL AND expires_at > $2
+        RETURNING id, command"#,
+        hostname.as_ref(),
+        claimed_at,
+    )
+    .fetch_all(db)
+    .await?;
+    if claimed.is_empty() {
+        return Ok(());
+    }
+    claimed.sort_unstable_by_key(|c| c.id);
+    tracing::info!(n = claimed.len(), "replaying queued commands");
+
+    for Claimed { id, command } in claimed {
+        let response = match serde_json::from_str::<Command>(&command) {
+            Ok(command) => match relay_command(&socket, &command, SEND_TIMEOUT).await {
+                Ok(response) => response,
+                Err(e @ (RelayFailure::SocketClosed | RelayFailure::ChannelFull)) => {
+                    tracing::warn!(id, error = ?e, "spark went away, keeping the rest queued");
+                    sqlx::query!(
+                        r#"UPDATE queued_commands SET sent_at = NULL
+                        WHERE hostname = $1 AND sent_at = $2 AND response IS NULL"#,
+                        hostname.as_ref(),
+                        claimed_at,
+                    )
+                    .execute(db)
+                    .await?;
+                    return Ok(());
+                }
+                Err(e) => Err(e.into()),
+            },
+            // this version of the server doesn't know the command anymore
+            Err(e) => Err(spark_protocol::ErrorResponse::RelayError(format!(
+                "queued command is no longer valid: {e}"
+            ))),
+        };
+        sqlx::query!(
+            r#"UPDATE queued_commands SET response = $2 WHERE id = $1"#,
+            id,
+            serde_json::to_string(&response).expect("responses always serialize"),
+        )
+        .execute(db)
+        .await?;
+    }
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{collections::BTreeSet, sync::Arc};
 
 use common::{domain::Hostname, ws};
 use serde::Deserialize;
@@ -7,9 +7,14 @@ use socketioxide::{
     handler::ConnectHandler,
     socket::DisconnectReason,
 };
+use spark_protocol::{Capabilities, relay};
 use sqlx::PgPool;
+use tokio::sync::mpsc::error::TrySendError;
 
-use crate::{metrics, persistent_connections::Generation};
+use crate::{
+    metrics,
+    persistent_connections::{Generation, Subscriptions, Tags, queue},
+};
 
 pub type SocketIo = socketioxide::SocketIo<socketioxide::adapter::LocalAdapter>;
 
@@ -40,22 +45,50 @@ async fn hostname_middleware(s: SocketRef) -> Result<(), &'static str> {
 #[derive(Deserialize)]
 struct Auth {
     token: uuid::Uuid,
+    /// Sparks older than the handshake don't send this.
+    #[serde(default)]
+    capabilities: Option<Capabilities>,
+    #[serde(default)]
+    tags: BTreeSet<String>,
 }
 
 #[tracing::instrument(skip_all, fields(auth = ?auth.token))]
 async fn auth_middleware(
+    s: SocketRef,
     auth: Data<Auth>,
     State(db): State<Arc<PgPool>>,
 ) -> Result<(), crate::auth::AuthError> {
     let r = crate::auth::check_token::<crate::auth::Admin>(&db, auth.token).await;
     tracing::info!("authenticated? {}", r.is_ok());
+    if let Some(capabilities) = &auth.capabilities {
+        tracing::info!(?capabilities, "spark advertised capabilities");
+        s.extensions.insert(capabilities.clone());
+    }
+    s.extensions.insert(Tags(Arc::new(auth.tags.clone())));
     Ok(())
 }
 
 #[tracing::instrument(skip_all)]
-fn on_connect(socket: SocketRef, hostname: Extension<SHostname>) {
+fn on_connect(socket: SocketRef, hostname: Extension<SHostname>, State(db): State<Arc<PgPool>>) {
     tracing::info!(hostname = %*hostname, sid = %socket.id, "socket connected");
 
+    if let Err(e) = socket.emit(ws::HANDSHAKE, &Capabilities::all()) {
+        tracing::warn!(hostname = %*hostname, error = ?e, "failed to send handshake");
+    }
+
+    socket.extensions.insert(Subscriptions::default());
+    socket.on(ws::EVENT, on_event);
+
+    tokio::spawn({
+        let socket = socket.clone();
+        let hostname = SHostname::clone(&hostname);
+        async move {
+            if let Err(e) = queue::replay(&db, socket, &hostname).await {
+                tracing::error!(%hostname, error = ?e, "failed to replay queued commands");
+            }
+        }
+    });
+
     socket.on_disconnect(
         |s: SocketRef, reason: DisconnectReason, hostname: Extension<SHostname>| {
             metrics::persistent_connections().dec();
@@ -70,6 +103,28 @@ fn on_connect(socket: SocketRef, hostname: Extension<SHostname>) {
     );
 }
 
+#[tracing::instrument(skip_all)]
+fn on_event(Data(event): Data<relay::Event>, subscriptions: Extension<Subscriptions>) {
+    let Some(tx) = subscriptions.get(event.id) else {
+        tracing::debug!(
+            id = event.id,
+            "event for a subscription nobody is listening to"
+        );
+        return;
+    };
+    match tx.try_send(event.response) {
+        Ok(()) => {}
+        Err(TrySendError::Full(response)) => {
+            tracing::warn!(
+                id = event.id,
+                ?response,
+                "subscriber is lagging behind, dropping event"
+            );
+        }
+        Err(TrySendError::Closed(_)) => subscriptions.remove(event.id),
+    }
+}
+
 pub fn socket_io_routes(db: Arc<PgPool>) -> (socketioxide::layer::SocketIoLayer, SocketIo) {
     let (layer, io) = socketioxide::SocketIo::builder()
         .with_state(db)
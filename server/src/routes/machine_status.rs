@@ -1,6 +1,7 @@
 use std::collections::hash_map::Entry;
 
 use std::sync::Arc;
+use std::time::Duration;
 use std::{
     collections::HashMap,
     convert::{TryFrom, TryInto},
@@ -9,7 +10,7 @@ use std::{
 use anyhow::Context;
 use axum::{Json, Router, extract::State, response::IntoResponse, routing};
 use chrono::Utc;
-use common::domain::machine_status::{self, IpConnection, MachineStatusFull};
+use common::domain::machine_status::{self, IpConnection, MachineStatusFull, PeerProbe};
 use futures::stream::{StreamExt, TryStreamExt};
 use http::StatusCode;
 use sqlx::PgPool;
@@ -84,6 +85,29 @@ pub async fn post(
         .await
         .context("Failed to insert new ips")?;
     }
+
+    sqlx::query!(
+        r#"DELETE FROM peer_probe WHERE hostname = $1"#,
+        status.hostname.as_ref()
+    )
+    .execute(transaction.as_mut())
+    .await
+    .context("Failed to delete old peer probes")?;
+
+    for p in status.peers {
+        sqlx::query!(
+            r#"INSERT INTO peer_probe (hostname, peer, rtt_micros)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (hostname, peer) DO UPDATE SET rtt_micros = $3"#,
+            status.hostname.as_ref(),
+            p.hostname.as_ref(),
+            p.rtt
+                .map(|rtt| i64::try_from(rtt.as_micros()).unwrap_or(i64::MAX)),
+        )
+        .execute(transaction.as_mut())
+        .await
+        .context("Failed to insert new peer probes")?;
+    }
     transaction
         .commit()
         .await
@@ -96,7 +120,7 @@ pub async fn get(
     _: auth::Admin,
     conn: State<Arc<PgPool>>,
 ) -> Result<impl IntoResponse, MachineStatusError> {
-    let status = sqlx::query!(
+    let mut status = sqlx::query!(
         r#"SELECT
             ms.hostname as "hostname!",
             external_ip as "external_ip!",
@@ -134,6 +158,7 @@ pub async fn get(
                                 external_ip,
                                 ip_connections: vec![],
                                 default_user: record.default_user,
+                                peers: vec![],
                             },
                             last_heartbeat: record.last_heartbeat.and_utc(),
                         })
@@ -156,5 +181,21 @@ pub async fn get(
     )
     .await?;
 
+    let probes = sqlx::query!(r#"SELECT hostname, peer, rtt_micros FROM peer_probe"#)
+        .fetch_all(&**conn)
+        .await
+        .context("failed to fetch peer probes")?;
+    for probe in probes {
+        if let Some(s) = status.get_mut(&probe.hostname) {
+            s.fields.peers.push(PeerProbe {
+                hostname: probe.peer.try_into().context("parse peer hostname")?,
+                rtt: probe
+                    .rtt_micros
+                    .and_then(|us| u64::try_from(us).ok())
+                    .map(Duration::from_micros),
+            });
+        }
+    }
+
     Ok((StatusCode::OK, Json(status)))
 }
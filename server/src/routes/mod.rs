@@ -4,6 +4,7 @@ pub mod machine_status;
 pub mod music;
 pub mod persistent_connections;
 pub mod playlist;
+pub mod spark;
 pub mod walls;
 
 use std::sync::Arc;
@@ -143,6 +144,7 @@ pub mod dirs {
         files/ {
             unlisted
         }
+        spark/ {}
     }
 }
 
@@ -169,6 +171,7 @@ pub fn router(db: Arc<PgPool>, socket_io: SocketIo, dirs: dirs::Directories, api
         .nest("/playlist", playlist::routes())
         .nest("/walls", walls::routes())
         .nest("/files", files::routes())
+        .nest("/spark", spark::routes())
         .with_state(RouterState {
             db,
             socket_io,
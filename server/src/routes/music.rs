@@ -5,12 +5,14 @@ use axum::{
     routing::post,
 };
 use http::StatusCode;
-use spark_protocol::music::MusicCmdKind;
+use spark_protocol::music::{MusicCmdKind, Subscription};
 
 use crate::auth::{self, music_session::MusicSession};
 
 pub fn routes() -> Router<super::RouterState> {
-    Router::new().route("/ws/{id}", post(ws_message_music_player))
+    Router::new()
+        .route("/ws/{id}", post(ws_message_music_player))
+        .route("/ws/{id}/subscribe", post(ws_subscribe_music_player))
 }
 
 async fn ws_message_music_player(
@@ -25,11 +27,30 @@ async fn ws_message_music_player(
         Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     };
 
-    super::persistent_connections::ws_send(
+    super::persistent_connections::send(&socket_io, &db, &hostname, &command.into(), None).await
+}
+
+async fn ws_subscribe_music_player(
+    State(super::RouterState { socket_io, db, .. }): State<super::RouterState>,
+    Path(id): Path<MusicSession>,
+    Json(Subscription { events, .. }): Json<Subscription>,
+) -> axum::response::Response {
+    let hostname = match id.hostname(&db).await {
+        Ok(Some(h)) => h,
+        Ok(None) => return StatusCode::UNAUTHORIZED.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    // sessions only get to listen to the default player, like they only get to control it
+    super::persistent_connections::ws_subscribe(
         auth::Admin {},
         State(socket_io),
         Path(hostname),
-        Json(command.into()),
+        Json(Subscription {
+            events,
+            index: None,
+            username: None,
+        }),
     )
     .await
 }
@@ -1,19 +1,41 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    convert::Infallible,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use axum::{
     Json, Router,
-    extract::{Path, State},
+    body::Body,
+    extract::{Path, Query, State},
     response::IntoResponse,
     routing::{get, post},
 };
 use common::{domain::Hostname, ws};
-use http::StatusCode;
+use futures::{
+    Stream, StreamExt,
+    future::{self, ready},
+    stream,
+};
+use http::{StatusCode, header};
+use serde::Deserialize;
 use socketioxide::{AckError, SendError, SocketError, extract::SocketRef};
+use spark_protocol::{
+    Capabilities, Command, ErrorResponse, UNKNOWN_PROTOCOL_VERSION,
+    music::Subscription,
+    relay::{self, Broadcast, BroadcastResponse, QueuedId, SubscriptionId},
+};
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 
 use crate::{
     auth,
     persistent_connections::{
-        Generation,
+        Generation, Subscriptions, Tags, queue,
         ws::{SHostname, SocketIo},
     },
 };
@@ -23,7 +45,10 @@ pub fn routes() -> Router<super::RouterState> {
         "/ws",
         Router::new()
             .route("/", get(ws_list_persistent_connections))
-            .route("/send/{hostname}", post(ws_send)),
+            .route("/send/{hostname}", post(ws_send))
+            .route("/queued/{id}", get(ws_queued))
+            .route("/broadcast", post(ws_broadcast))
+            .route("/subscribe/{hostname}", post(ws_subscribe)),
     )
 }
 
@@ -44,12 +69,8 @@ async fn ws_list_persistent_connections(
     )
 }
 
-pub async fn ws_send(
-    _: auth::Admin,
-    State(io): State<SocketIo>,
-    hostname: Path<Hostname>,
-    Json(command): Json<spark_protocol::Command>,
-) -> axum::response::Response {
+/// The most recent socket opened by `hostname`.
+fn find_socket(io: &SocketIo, hostname: &Hostname) -> Option<SocketRef> {
     let sockets = io.of(ws::NS).unwrap().sockets();
     tracing::warn!("socket#: {}", sockets.len());
     let by_hostname = |s: &SocketRef| {
@@ -58,26 +79,141 @@ pub async fn ws_send(
             .is_some_and(|h| *h == *hostname)
     };
     let generation = |s: &SocketRef| s.extensions.get::<Generation>().unwrap();
-    let socket = match sockets
+    sockets
         .into_iter()
         .filter(by_hostname)
         .max_by_key(generation)
-    {
-        Some(ns) => ns,
-        None => return StatusCode::NOT_FOUND.into_response(),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendOptions {
+    /// If the spark isn't connected, queue the command for up to this many seconds instead of
+    /// failing.
+    queue_for: Option<u64>,
+}
+
+pub async fn ws_send(
+    _: auth::Admin,
+    State(io): State<SocketIo>,
+    State(db): State<Arc<PgPool>>,
+    Path(hostname): Path<Hostname>,
+    Query(SendOptions { queue_for }): Query<SendOptions>,
+    Json(command): Json<spark_protocol::Command>,
+) -> axum::response::Response {
+    send(
+        &io,
+        &db,
+        &hostname,
+        &command,
+        queue_for.map(Duration::from_secs),
+    )
+    .await
+}
+
+/// Relays `command` to `hostname` and responds with what the spark answered. If the spark isn't
+/// connected the command is queued for `queue_for`, if given.
+pub(crate) async fn send(
+    io: &SocketIo,
+    db: &PgPool,
+    hostname: &Hostname,
+    command: &Command,
+    queue_for: Option<Duration>,
+) -> axum::response::Response {
+    let Some(socket) = find_socket(io, hostname) else {
+        let Some(ttl) = queue_for else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        return match queue::enqueue(db, hostname, command, ttl).await {
+            Ok(queued) => (StatusCode::ACCEPTED, Json(queued)).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        };
     };
+    match relay_command(&socket, command, SEND_TIMEOUT).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Where a command queued by [`ws_send`] is at.
+pub async fn ws_queued(
+    _: auth::Admin,
+    State(db): State<Arc<PgPool>>,
+    Path(id): Path<QueuedId>,
+) -> axum::response::Response {
+    match queue::status(&db, id).await {
+        Ok(Some(delivery)) => Json(delivery).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+pub(crate) const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+const BROADCAST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a spark's response couldn't be relayed back.
+#[derive(Debug)]
+pub(crate) enum RelayFailure {
+    Timeout(Duration),
+    SocketClosed,
+    ChannelFull,
+    Decode(String),
+}
+
+impl IntoResponse for RelayFailure {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT.into_response(),
+            Self::SocketClosed => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "socket closed").into_response()
+            }
+            Self::ChannelFull => StatusCode::TOO_MANY_REQUESTS.into_response(),
+            Self::Decode(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        }
+    }
+}
+
+impl From<RelayFailure> for ErrorResponse {
+    fn from(failure: RelayFailure) -> Self {
+        Self::RelayError(match failure {
+            RelayFailure::Timeout(timeout) => {
+                format!("no response after {}s", timeout.as_secs_f64())
+            }
+            RelayFailure::SocketClosed => "socket closed".into(),
+            RelayFailure::ChannelFull => "too many requests".into(),
+            RelayFailure::Decode(e) => e,
+        })
+    }
+}
+
+impl From<SocketError> for RelayFailure {
+    fn from(e: SocketError) -> Self {
+        match e {
+            SocketError::Closed => Self::SocketClosed,
+            SocketError::InternalChannelFull => Self::ChannelFull,
+        }
+    }
+}
+
+/// Sends a command to the spark behind `socket` and waits for its response, unless the spark
+/// advertised that it doesn't support it.
+pub(crate) async fn relay_command(
+    socket: &SocketRef,
+    command: &Command,
+    timeout: Duration,
+) -> Result<spark_protocol::Response, RelayFailure> {
+    if let Some(capabilities) = socket.extensions.get::<Capabilities>()
+        && let Err(e) = capabilities.check(command)
+    {
+        tracing::info!(?command, "remote spark does not support command");
+        return Ok(Err(e));
+    }
     tracing::info!(?command, "sending message to ws");
     let emit_future = socket
-        .timeout(Duration::from_secs(60))
-        .emit_with_ack::<_, [spark_protocol::Response; 1]>(ws::COMMAND, &command);
+        .timeout(timeout)
+        .emit_with_ack::<_, [spark_protocol::Response; 1]>(ws::COMMAND, command);
     let response = match emit_future {
         Ok(future) => future.await,
-        Err(SendError::Socket(SocketError::Closed)) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR, "socket closed").into_response();
-        }
-        Err(SendError::Socket(SocketError::InternalChannelFull)) => {
-            return StatusCode::TOO_MANY_REQUESTS.into_response();
-        }
+        Err(SendError::Socket(e)) => return Err(e.into()),
         Err(SendError::Serialize(e)) => {
             panic!("should never fail to serialize a command: {e:?}")
         }
@@ -86,16 +222,164 @@ pub async fn ws_send(
     tracing::info!(?response, "received response");
 
     match response {
-        Ok([data]) => (StatusCode::OK, Json(data)).into_response(),
-        Err(AckError::Timeout) => StatusCode::GATEWAY_TIMEOUT.into_response(),
-        Err(AckError::Decode(e)) => {
-            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        Ok([data]) => Ok(data),
+        Err(AckError::Timeout) => Err(RelayFailure::Timeout(timeout)),
+        Err(AckError::Decode(e)) => Err(RelayFailure::Decode(e.to_string())),
+        Err(AckError::Socket(e)) => Err(e.into()),
+    }
+}
+
+/// Sends a command to every spark that matches the broadcast's targets at once, and collects
+/// what each of them replied.
+pub async fn ws_broadcast(
+    _: auth::Admin,
+    State(io): State<SocketIo>,
+    Json(Broadcast {
+        targets,
+        command,
+        timeout,
+    }): Json<Broadcast>,
+) -> Json<BroadcastResponse> {
+    let timeout = timeout.unwrap_or(BROADCAST_TIMEOUT);
+    let no_tags = Tags::default();
+    let mut latest = HashMap::<SHostname, SocketRef>::new();
+    for socket in io.of(ws::NS).unwrap().sockets() {
+        let Some(hostname) = socket.extensions.get::<SHostname>() else {
+            continue;
+        };
+        let tags = socket.extensions.get::<Tags>();
+        if !targets.matches(&hostname, &tags.as_ref().unwrap_or(&no_tags).0) {
+            continue;
+        }
+        let generation = |s: &SocketRef| s.extensions.get::<Generation>();
+        match latest.entry(hostname) {
+            Entry::Occupied(mut e) => {
+                if generation(&socket) > generation(e.get()) {
+                    e.insert(socket);
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert(socket);
+            }
         }
-        Err(AckError::Socket(SocketError::Closed)) => {
-            (StatusCode::INTERNAL_SERVER_ERROR, "socket closed").into_response()
+    }
+    tracing::info!(?command, hosts = latest.len(), "broadcasting");
+    let responses = future::join_all(latest.into_iter().map(|(hostname, socket)| {
+        let command = &command;
+        async move {
+            let response = relay_command(&socket, command, timeout)
+                .await
+                .unwrap_or_else(|e| Err(e.into()));
+            (Hostname::clone(&hostname), response)
         }
-        Err(AckError::Socket(SocketError::InternalChannelFull)) => {
-            StatusCode::TOO_MANY_REQUESTS.into_response()
+    }))
+    .await;
+    Json(responses.into_iter().collect())
+}
+
+/// Relays a subscription to the spark and streams its events back as json lines, until either
+/// side hangs up.
+pub async fn ws_subscribe(
+    _: auth::Admin,
+    State(io): State<SocketIo>,
+    hostname: Path<Hostname>,
+    Json(subscription): Json<Subscription>,
+) -> axum::response::Response {
+    let Some(socket) = find_socket(&io, &hostname) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // sparks that don't advertise their capabilities predate subscriptions
+    let supported = match socket.extensions.get::<Capabilities>() {
+        Some(capabilities) => capabilities.check(&Command::Subscribe(subscription.clone())),
+        None => Err(ErrorResponse::Unsupported {
+            command: "Subscribe".into(),
+            protocol_version: UNKNOWN_PROTOCOL_VERSION,
+        }),
+    };
+    if let Err(e) = supported {
+        tracing::info!(?subscription, "remote spark does not support subscriptions");
+        return json_lines(stream::once(ready(Err(e))));
+    }
+    let Some(subscriptions) = socket.extensions.get::<Subscriptions>() else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "socket not ready").into_response();
+    };
+
+    let id = Subscriptions::next_id();
+    let (tx, rx) = mpsc::channel(32);
+    subscriptions.insert(id, tx);
+    tracing::info!(id, ?subscription, "subscribing through ws");
+    if let Err(e) = socket.emit(ws::SUBSCRIBE, &relay::Subscribe { id, subscription }) {
+        subscriptions.remove(id);
+        return match e {
+            SendError::Socket(SocketError::Closed) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "socket closed").into_response()
+            }
+            SendError::Socket(SocketError::InternalChannelFull) => {
+                StatusCode::TOO_MANY_REQUESTS.into_response()
+            }
+            SendError::Serialize(e) => {
+                panic!("should never fail to serialize a subscription: {e:?}")
+            }
+        };
+    }
+
+    let unsubscribe = Unsubscribe {
+        socket,
+        subscriptions,
+        id,
+    };
+    json_lines(SubscriptionStream {
+        events: ReceiverStream::new(rx),
+        _unsubscribe: unsubscribe,
+    })
+}
+
+/// The events of a subscription, the spark is told to stop once the response body holding
+/// this is dropped.
+struct SubscriptionStream {
+    events: ReceiverStream<spark_protocol::Response>,
+    _unsubscribe: Unsubscribe,
+}
+
+impl Stream for SubscriptionStream {
+    type Item = spark_protocol::Response;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+struct Unsubscribe {
+    socket: SocketRef,
+    subscriptions: Subscriptions,
+    id: SubscriptionId,
+}
+
+impl Drop for Unsubscribe {
+    fn drop(&mut self) {
+        tracing::info!(id = self.id, "unsubscribing from ws");
+        self.subscriptions.remove(self.id);
+        if let Err(e) = self
+            .socket
+            .emit(ws::UNSUBSCRIBE, &relay::Unsubscribe { id: self.id })
+        {
+            tracing::warn!(id = self.id, error = ?e, "failed to unsubscribe");
         }
     }
 }
+
+fn json_lines<S>(responses: S) -> axum::response::Response
+where
+    S: Stream<Item = spark_protocol::Response> + Send + 'static,
+{
+    let body = responses.map(|response| {
+        let mut line = serde_json::to_vec(&response).expect("responses always serialize");
+        line.push(b'\n');
+        Ok::<_, Infallible>(line)
+    });
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
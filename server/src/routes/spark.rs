@@ -0,0 +1,153 @@
+//! Spark builds that running sparks can update themselves to, by target triple and version.
+//!
+//! Each build lives in its own `{target}/{version}` directory holding the binary and its
+//! metadata. Builds are never overwritten, so the metadata a spark fetched always describes the
+//! binary it downloads afterwards.
+
+use std::io;
+
+use axum::{
+    Json, Router,
+    body::{Body, to_bytes},
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::get,
+};
+use common::domain::spark_build::{SparkBuild, is_valid_target, is_valid_version};
+use http::StatusCode;
+
+use crate::{
+    auth,
+    routes::{RouterState, dirs::Directory as _},
+};
+
+/// Largest binary that can be uploaded.
+const MAX_BUILD_SIZE: usize = 256 * 1024 * 1024;
+
+pub fn routes() -> Router<RouterState> {
+    Router::new()
+        .route("/builds/{target}/{version}", get(download).post(upload))
+        .route("/builds/{target}/{version}/meta", get(meta))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("io: {0}")]
+    Io(#[from] io::Error),
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0} is not a valid target triple")]
+    InvalidTarget(String),
+    #[error("{0} is not a valid version")]
+    InvalidVersion(String),
+    #[error("no build of {1} was uploaded for {0}")]
+    NotFound(String, String),
+    #[error("{1} was already uploaded for {0}")]
+    AlreadyUploaded(String, String),
+    #[error("failed to read body: {0}")]
+    Body(String),
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            Self::Io(_) | Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidTarget(_) | Self::InvalidVersion(_) | Self::Body(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::NotFound(..) => StatusCode::NOT_FOUND,
+            Self::AlreadyUploaded(..) => StatusCode::CONFLICT,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+fn validate(target: String, version: String) -> Result<(String, String), Error> {
+    if !is_valid_target(&target) {
+        Err(Error::InvalidTarget(target))
+    } else if !is_valid_version(&version) {
+        Err(Error::InvalidVersion(version))
+    } else {
+        Ok((target, version))
+    }
+}
+
+const BINARY: &str = "spark";
+const META: &str = "meta.json";
+
+#[tracing::instrument(skip(state, body))]
+async fn upload(
+    _: auth::Admin,
+    state: State<RouterState>,
+    Path((target, version)): Path<(String, String)>,
+    body: Body,
+) -> Result<impl IntoResponse, Error> {
+    let (target, version) = validate(target, version)?;
+    let binary = to_bytes(body, MAX_BUILD_SIZE)
+        .await
+        .map_err(|e| Error::Body(e.to_string()))?;
+    let build = SparkBuild {
+        target: target.clone(),
+        version: version.clone(),
+        checksum: spark_protocol::files::checksum(&binary),
+        size: binary.len() as u64,
+    };
+
+    // the build is put together in a scratch directory and renamed into place as a whole, so
+    // the binary and its metadata show up at the same time
+    let builds = state.dirs.spark().file(&target);
+    let build_dir = builds.join(&version);
+    if tokio::fs::try_exists(&build_dir).await? {
+        return Err(Error::AlreadyUploaded(target, version));
+    }
+    let scratch = builds.join(format!(".{version}.{}.upload", uuid::Uuid::new_v4()));
+    tokio::fs::create_dir_all(&scratch).await?;
+    let written = async {
+        tokio::fs::write(scratch.join(BINARY), &binary).await?;
+        tokio::fs::write(scratch.join(META), serde_json::to_vec(&build)?).await?;
+        tokio::fs::rename(&scratch, &build_dir).await?;
+        Ok::<_, Error>(())
+    }
+    .await;
+    if let Err(e) = written {
+        if let Err(e) = tokio::fs::remove_dir_all(&scratch).await {
+            tracing::warn!(error = ?e, "failed to clean up the upload");
+        }
+        // another upload of the same version won the rename
+        return match tokio::fs::try_exists(&build_dir).await {
+            Ok(true) => Err(Error::AlreadyUploaded(target, version)),
+            _ => Err(e),
+        };
+    }
+
+    tracing::info!(?build, "spark build uploaded");
+    Ok(Json(build))
+}
+
+async fn meta(
+    _: auth::Admin,
+    state: State<RouterState>,
+    Path((target, version)): Path<(String, String)>,
+) -> Result<impl IntoResponse, Error> {
+    let (target, version) = validate(target, version)?;
+    let path = state.dirs.spark().file(&target).join(&version).join(META);
+    match tokio::fs::read(path).await {
+        Ok(meta) => Ok(Json(serde_json::from_slice::<SparkBuild>(&meta)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::NotFound(target, version)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn download(
+    _: auth::Admin,
+    state: State<RouterState>,
+    Path((target, version)): Path<(String, String)>,
+) -> Result<impl IntoResponse, Error> {
+    let (target, version) = validate(target, version)?;
+    let path = state.dirs.spark().file(&target).join(&version).join(BINARY);
+    match common::web_server::named_file(path).await {
+        Ok(f) => Ok(f),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::NotFound(target, version)),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -199,6 +199,83 @@ impl TestApp {
         resp.json().await.expect("deserialized successfully")
     }
 
+    /// Sends `cmd` to `hostname`, which isn't connected, queueing it for `ttl` seconds.
+    pub async fn queue_cmd(
+        &self,
+        hostname: Hostname,
+        cmd: impl Into<spark_protocol::Command>,
+        ttl: u64,
+    ) -> spark_protocol::relay::Queued {
+        let resp = self
+            .post_authed(&format!(
+                "persistent-connections/ws/send/{hostname}?queue_for={ttl}"
+            ))
+            .json(&cmd.into())
+            .send()
+            .await
+            .expect("success");
+        assert_status!(StatusCode::ACCEPTED, resp.status());
+        resp.json().await.expect("deserialized successfully")
+    }
+
+    pub async fn queued(
+        &self,
+        id: spark_protocol::relay::QueuedId,
+    ) -> spark_protocol::relay::Delivery {
+        let resp = self
+            .get_authed(&format!("persistent-connections/ws/queued/{id}"))
+            .send()
+            .await
+            .expect("success");
+        assert_status!(StatusCode::OK, resp.status());
+        resp.json().await.expect("deserialized successfully")
+    }
+
+    pub async fn broadcast(
+        &self,
+        broadcast: spark_protocol::relay::Broadcast,
+    ) -> spark_protocol::relay::BroadcastResponse {
+        let resp = self
+            .post_authed("persistent-connections/ws/broadcast")
+            .json(&broadcast)
+            .send()
+            .await
+            .expect("success");
+        assert_status!(StatusCode::OK, resp.status());
+        resp.json().await.expect("deserialized successfully")
+    }
+
+    /// Subscribes to `hostname` and waits for `n` events.
+    pub async fn subscribe(
+        &self,
+        hostname: Hostname,
+        subscription: spark_protocol::music::Subscription,
+        n: usize,
+    ) -> Vec<spark_protocol::Response> {
+        let mut resp = self
+            .post_authed(&format!("persistent-connections/ws/subscribe/{hostname}"))
+            .json(&subscription)
+            .send()
+            .await
+            .expect("success");
+        assert_status!(StatusCode::OK, resp.status());
+        let mut buf = Vec::new();
+        let mut events = Vec::new();
+        while events.len() < n {
+            let chunk = resp
+                .chunk()
+                .await
+                .expect("chunk")
+                .expect("stream ended early");
+            buf.extend_from_slice(&chunk);
+            while let Some(i) = buf.iter().position(|b| *b == b'\n') {
+                let line = buf.drain(..=i).collect::<Vec<_>>();
+                events.push(serde_json::from_slice(&line).expect("deserialized successfully"));
+            }
+        }
+        events
+    }
+
     pub async fn simulate_device_ws<C, R>(
         &self,
         Simulation {
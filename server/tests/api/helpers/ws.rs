@@ -3,15 +3,52 @@ use common::{domain::Hostname, ws};
 use futures::{FutureExt as _, executor::block_on};
 use rust_socketio::asynchronous::ClientBuilder;
 use serde_json::json;
-use spark_protocol::{Command, Response};
+use spark_protocol::{Capabilities, Command, Response, relay};
 use tokio::sync::mpsc;
 
 impl TestApp {
     pub async fn connect_device_ws(&self, hostname: &Hostname) -> Device {
+        self.connect_device_ws_with_auth(hostname, json!({ "token": self.auth_token }), vec![])
+            .await
+    }
+
+    /// Connects a device that answers every subscription with `events`.
+    pub async fn connect_device_ws_with_events(
+        &self,
+        hostname: &Hostname,
+        events: Vec<Response>,
+    ) -> Device {
+        self.connect_device_ws_with_auth(
+            hostname,
+            json!({ "token": self.auth_token, "capabilities": Capabilities::all() }),
+            events,
+        )
+        .await
+    }
+
+    pub async fn connect_device_ws_with_capabilities(
+        &self,
+        hostname: &Hostname,
+        capabilities: Capabilities,
+    ) -> Device {
+        self.connect_device_ws_with_auth(
+            hostname,
+            json!({ "token": self.auth_token, "capabilities": capabilities }),
+            vec![],
+        )
+        .await
+    }
+
+    async fn connect_device_ws_with_auth(
+        &self,
+        hostname: &Hostname,
+        auth: serde_json::Value,
+        events: Vec<Response>,
+    ) -> Device {
         tracing::debug!("connecting to web socket as {hostname}");
         let (tx, rx) = mpsc::channel(1);
         let socket = ClientBuilder::new(format!("{}?h={hostname}", self.address))
-            .auth(json!({ "token": self.auth_token }))
+            .auth(auth)
             .namespace(ws::NS)
             .on_with_ack(ws::COMMAND, move |payload, socket, ack| {
                 let tx = tx.clone();
@@ -20,6 +57,23 @@ impl TestApp {
                 }
                 .boxed()
             })
+            .on(ws::SUBSCRIBE, move |payload, socket| {
+                let events = events.clone();
+                async move {
+                    let rust_socketio::Payload::Text(mut v) = payload else {
+                        panic!("unexpected payload type");
+                    };
+                    let relay::Subscribe { id, .. } = serde_json::from_value(v.remove(0)).unwrap();
+                    for response in events {
+                        let event = relay::Event { id, response };
+                        socket
+                            .emit(ws::EVENT, serde_json::to_string(&event).unwrap())
+                            .await
+                            .unwrap();
+                    }
+                }
+                .boxed()
+            })
             .on("error", |err, _| panic!("error occurred: {err:?}"))
             .connect()
             .await
@@ -186,3 +186,35 @@ async fn machine_status_returns_posted_status() {
         assert!(o["ip_connections"].as_array().expect("array").is_empty());
     }
 }
+
+#[tokio::test]
+async fn machine_status_returns_peer_probes() {
+    let app = TestApp::spawn().await;
+    let mut body = well_formed_json();
+    let (reachable, unreachable) = (fake_hostname(), fake_hostname());
+    body["peers"] = json!([
+        { "hostname": reachable, "rtt": { "secs": 0, "nanos": 2_000_000 } },
+        { "hostname": unreachable, "rtt": null },
+    ]);
+
+    assert_eq!(
+        app.post_machine_status(&body).await.status(),
+        StatusCode::OK
+    );
+
+    let returned = app
+        .get("machine/status")
+        .bearer_auth(app.auth_token)
+        .send()
+        .await
+        .expect("failed to execute request")
+        .json::<serde_json::Value>()
+        .await
+        .expect("didn't parse");
+    let mut peers = returned[body["hostname"].as_str().unwrap()]["peers"]
+        .as_array()
+        .expect("peers to be returned")
+        .clone();
+    peers.sort_by_key(|p| p["rtt"].is_null());
+    assert_eq!(serde_json::Value::Array(peers), body["peers"]);
+}
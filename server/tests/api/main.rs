@@ -3,4 +3,5 @@ mod health_check;
 mod helpers;
 mod machine_status;
 mod music_players;
+mod spark_builds;
 mod ws_persistent_connections;
@@ -0,0 +1,98 @@
+use blind_eternities::auth;
+use common::domain::spark_build::SparkBuild;
+use reqwest::StatusCode;
+
+use crate::{assert_status, helpers::TestApp};
+
+const TARGET: &str = "arm-unknown-linux-gnueabihf";
+
+#[tokio::test]
+async fn uploaded_builds_can_be_downloaded() {
+    let app = TestApp::spawn().await;
+    let binary = b"\x7fELF not really a binary".to_vec();
+
+    let resp = app
+        .post_authed(&format!("spark/builds/{TARGET}/0.5.21"))
+        .body(binary.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_status!(StatusCode::OK, resp.status());
+
+    let resp = app
+        .get_authed(&format!("spark/builds/{TARGET}/0.5.21/meta"))
+        .send()
+        .await
+        .unwrap();
+    assert_status!(StatusCode::OK, resp.status());
+    assert_eq!(
+        resp.json::<SparkBuild>().await.unwrap(),
+        SparkBuild {
+            target: TARGET.into(),
+            version: "0.5.21".into(),
+            checksum: spark_protocol::files::checksum(&binary),
+            size: binary.len() as u64,
+        }
+    );
+
+    let resp = app
+        .get_authed(&format!("spark/builds/{TARGET}/0.5.21"))
+        .send()
+        .await
+        .unwrap();
+    assert_status!(StatusCode::OK, resp.status());
+    assert_eq!(resp.bytes().await.unwrap(), binary);
+}
+
+#[tokio::test]
+async fn builds_are_kept_per_version() {
+    let app = TestApp::spawn().await;
+    for (version, binary) in [("0.5.21", "old"), ("0.5.22", "new")] {
+        let resp = app
+            .post_authed(&format!("spark/builds/{TARGET}/{version}"))
+            .body(binary)
+            .send()
+            .await
+            .unwrap();
+        assert_status!(StatusCode::OK, resp.status());
+    }
+
+    let resp = app
+        .post_authed(&format!("spark/builds/{TARGET}/0.5.21"))
+        .body("replaced")
+        .send()
+        .await
+        .unwrap();
+    assert_status!(StatusCode::CONFLICT, resp.status());
+
+    let resp = app
+        .get_authed(&format!("spark/builds/{TARGET}/0.5.21"))
+        .send()
+        .await
+        .unwrap();
+    assert_status!(StatusCode::OK, resp.status());
+    assert_eq!(resp.bytes().await.unwrap(), "old");
+}
+
+#[tokio::test]
+async fn missing_builds_are_not_found() {
+    let app = TestApp::spawn().await;
+    let resp = app
+        .get_authed(&format!("spark/builds/{TARGET}/0.5.21/meta"))
+        .send()
+        .await
+        .unwrap();
+    assert_status!(StatusCode::NOT_FOUND, resp.status());
+}
+
+#[tokio::test]
+async fn only_admins_can_upload_builds() {
+    let app = TestApp::spawn().await.downgrade_to::<auth::Music>().await;
+    let resp = app
+        .post_authed(&format!("spark/builds/{TARGET}/0.5.21"))
+        .body("binary")
+        .send()
+        .await
+        .unwrap();
+    assert_status!(StatusCode::UNAUTHORIZED, resp.status());
+}
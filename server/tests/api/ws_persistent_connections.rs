@@ -1,7 +1,13 @@
+use std::{collections::BTreeMap, time::Duration};
+
 use common::domain::Hostname;
 use common::net::PERSISTENT_CONN_RECV_TIMEOUT;
 use reqwest::StatusCode;
-use spark_protocol::{Command, SuccessfulResponse};
+use spark_protocol::{
+    Capabilities, Command, ErrorResponse, PROTOCOL_VERSION, SuccessfulResponse,
+    UNKNOWN_PROTOCOL_VERSION, music,
+    relay::{Broadcast, Delivery, Targets},
+};
 
 use crate::helpers::{Simulation, TestApp, fake_hostname};
 use crate::{assert_status, timeout};
@@ -31,6 +37,136 @@ async fn requesting_version_works() {
     assert_eq!(response, expected_response);
 }
 
+#[tokio::test]
+async fn commands_the_device_does_not_support_are_rejected() {
+    let app = TestApp::spawn().await;
+
+    let hostname = fake_hostname();
+
+    let _device = timeout!(
+        app.connect_device_ws_with_capabilities(&hostname, Capabilities::all().without("Version"))
+    );
+
+    let response = timeout!(app.send_cmd(hostname, Command::Version));
+
+    assert_eq!(
+        response,
+        Err(ErrorResponse::Unsupported {
+            command: "Version".into(),
+            protocol_version: PROTOCOL_VERSION,
+        })
+    );
+}
+
+#[tokio::test]
+async fn subscriptions_relay_events_from_the_device() {
+    let app = TestApp::spawn().await;
+
+    let hostname = fake_hostname();
+
+    let events = vec![
+        Ok(music::Response::Title {
+            title: "song".into(),
+        }
+        .into()),
+        Ok(music::Response::PlayState { paused: true }.into()),
+    ];
+    let _device = timeout!(app.connect_device_ws_with_events(&hostname, events.clone()));
+
+    let subscription = music::Subscription {
+        events: vec![],
+        index: None,
+        username: None,
+    };
+    let received = timeout!(app.subscribe(hostname, subscription, events.len()));
+
+    assert_eq!(received, events);
+}
+
+#[tokio::test]
+async fn subscribing_to_devices_that_predate_subscriptions_is_rejected() {
+    let app = TestApp::spawn().await;
+
+    let hostname = fake_hostname();
+
+    let _device = timeout!(app.connect_device_ws(&hostname));
+
+    let subscription = music::Subscription {
+        events: vec![],
+        index: None,
+        username: None,
+    };
+    let received = timeout!(app.subscribe(hostname, subscription, 1));
+
+    assert_eq!(
+        received,
+        vec![Err(ErrorResponse::Unsupported {
+            command: "Subscribe".into(),
+            protocol_version: UNKNOWN_PROTOCOL_VERSION,
+        })]
+    );
+}
+
+#[tokio::test]
+async fn broadcasts_reach_every_device() {
+    let app = TestApp::spawn().await;
+
+    let hostnames = [fake_hostname(), fake_hostname()];
+    let mut devices = Vec::new();
+    for (i, hostname) in hostnames.iter().enumerate() {
+        devices.push(
+            app.simulate_device_ws(Simulation {
+                hostname,
+                expect_to_receive: Command::Version,
+                respond_with: Ok(SuccessfulResponse::Version(i.to_string())),
+            })
+            .await,
+        );
+    }
+
+    let responses = timeout!(app.broadcast(Broadcast {
+        targets: Targets::All,
+        command: Command::Version,
+        timeout: None,
+    }));
+
+    for device in devices {
+        device.await.expect("device task failed");
+    }
+
+    assert_eq!(
+        responses,
+        hostnames
+            .into_iter()
+            .enumerate()
+            .map(|(i, hostname)| (hostname, Ok(SuccessfulResponse::Version(i.to_string()))))
+            .collect::<BTreeMap<_, _>>()
+    );
+}
+
+#[tokio::test]
+async fn broadcasts_report_devices_that_time_out() {
+    let app = TestApp::spawn().await;
+
+    let hostname = fake_hostname();
+    let _device = timeout!(app.connect_device_ws(&hostname));
+    let _other = timeout!(app.connect_device_ws(&fake_hostname()));
+
+    let responses = timeout!(app.broadcast(Broadcast {
+        targets: Targets::Hosts(vec![hostname.to_string()]),
+        command: Command::Version,
+        timeout: Some(Duration::from_secs(1)),
+    }));
+
+    assert_eq!(
+        responses.into_iter().collect::<Vec<_>>(),
+        vec![(
+            hostname,
+            Err(ErrorResponse::RelayError("no response after 1s".into()))
+        )]
+    );
+}
+
 #[tokio::test]
 async fn list_connections_works() {
     let app = TestApp::spawn().await;
@@ -98,3 +234,57 @@ async fn list_connections_is_empty_after_device_quits() {
 
     assert!(list.is_empty(), "list: {list:?}");
 }
+
+#[tokio::test]
+async fn commands_for_disconnected_devices_are_delivered_once_they_connect() {
+    let app = TestApp::spawn().await;
+
+    let hostname = fake_hostname();
+
+    let queued = timeout!(app.queue_cmd(hostname.clone(), Command::Version, 60));
+    assert!(matches!(
+        timeout!(app.queued(queued.id)),
+        Delivery::Pending { .. }
+    ));
+
+    let expected_response = Ok(SuccessfulResponse::Version("queued".into()));
+    let device = app
+        .simulate_device_ws(Simulation {
+            hostname: &hostname,
+            expect_to_receive: Command::Version,
+            respond_with: expected_response.clone(),
+        })
+        .await;
+    device.await.expect("device task failed");
+
+    let delivery = timeout!(async {
+        loop {
+            match app.queued(queued.id).await {
+                Delivery::Delivered { response } => break *response,
+                _ => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    });
+
+    assert_eq!(delivery, expected_response);
+}
+
+#[tokio::test]
+async fn queued_commands_expire() {
+    let app = TestApp::spawn().await;
+
+    let hostname = fake_hostname();
+
+    let queued = timeout!(app.queue_cmd(hostname.clone(), Command::Version, 1));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let mut device = timeout!(app.connect_device_ws(&hostname));
+
+    assert_eq!(timeout!(app.queued(queued.id)), Delivery::Expired);
+    assert!(
+        tokio::time::timeout(Duration::from_secs(1), device.recv())
+            .await
+            .is_err(),
+        "expired command was delivered"
+    );
+}
@@ -1,11 +1,22 @@
+//! Writes the JSON Schemas of the wire format to `spark-protocol/schema`, or to the directory
+//! passed as argument.
+//!
+//! `schema --examples` prints an example of each message instead.
+
 use core::fmt;
-use std::time::Duration;
+use std::{fs, path::PathBuf, time::Duration};
 
 use mlib::queue::Current;
 use serde::Serialize;
 use spark_protocol::{
-    Command, ErrorResponse, SuccessfulResponse,
+    Capabilities, Command, ErrorResponse, PROTOCOL_VERSION, Reply, Request, SuccessfulResponse,
+    config::SetConfig,
+    exec::{Exec, ExecOutput},
+    files::{self, FileChunk, GetFile, PutFile},
     music::{self, MusicCmdKind},
+    schema,
+    status::{DaemonStatus, MachineStatusStatus, PersistentConnStatus},
+    system_info::{Battery, Disk, LoadAverage, Memory, SystemInfo},
 };
 
 fn display<T>(i: impl IntoIterator<Item = T>)
@@ -19,6 +30,20 @@ where
 }
 
 fn main() {
+    let arg = std::env::args().nth(1);
+    if arg.as_deref() == Some("--examples") {
+        return examples();
+    }
+    let dir = arg.map(PathBuf::from).unwrap_or_else(schema::dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (name, schema) in schema::all() {
+        let path = dir.join(schema::file_name(name));
+        fs::write(&path, schema::render(&schema)).unwrap();
+        println!("wrote {}", path.display());
+    }
+}
+
+fn examples() {
     let current = Current {
         title: "title".into(),
         artist: None,
@@ -33,49 +58,152 @@ fn main() {
         next: None,
     };
     display(
-        [Command::Reload, Command::Version, Command::Heartbeat]
+        [
+            Command::Reload,
+            Command::Version,
+            Command::Heartbeat,
+            Command::Handshake(Capabilities::all()),
+            Command::Subscribe(music::Subscription {
+                events: vec![music::EventKind::Title, music::EventKind::PlayState],
+                index: None,
+                username: None,
+            }),
+            Command::Exec(Exec {
+                program: "uptime".into(),
+                args: vec!["--pretty".into()],
+            }),
+            Command::PutFile(PutFile {
+                path: ".config/spark/config".into(),
+                offset: 0,
+                total_size: 4,
+                checksum: files::checksum(b"data"),
+                data: b"data".to_vec(),
+            }),
+            Command::GetFile(GetFile {
+                path: ".config/spark/config".into(),
+                offset: 0,
+            }),
+            Command::SystemInfo,
+            Command::Update {
+                version: "0.5.21".into(),
+            },
+            Command::GetConfig,
+            Command::Status,
+            Command::SetConfig(SetConfig {
+                patch: serde_json::json!({ "network": { "ssh": 2222 }, "default_user": null }),
+            }),
+            Command::Custom {
+                name: "toggle-vpn".into(),
+                args: vec!["work".into()],
+            },
+        ]
+        .into_iter()
+        .chain(
+            [
+                MusicCmdKind::Frwd,
+                MusicCmdKind::Back,
+                MusicCmdKind::CyclePause,
+                MusicCmdKind::Current,
+                MusicCmdKind::ChangeVolume { amount: 4 },
+                MusicCmdKind::Queue {
+                    query: "http://link".into(),
+                    search: false,
+                },
+                MusicCmdKind::Now { amount: Some(10) },
+                MusicCmdKind::Now { amount: None },
+                MusicCmdKind::Seek { seconds: 30 },
+                MusicCmdKind::Shuffle,
+                MusicCmdKind::Loop,
+                MusicCmdKind::Remove { index: 3 },
+                MusicCmdKind::Move { from: 3, to: 1 },
+                MusicCmdKind::Clear,
+                MusicCmdKind::Jump { index: 2 },
+                MusicCmdKind::ListPlayers,
+            ]
             .into_iter()
-            .chain(
+            .flat_map(|command| {
                 [
-                    MusicCmdKind::Frwd,
-                    MusicCmdKind::Back,
-                    MusicCmdKind::CyclePause,
-                    MusicCmdKind::Current,
-                    MusicCmdKind::ChangeVolume { amount: 4 },
-                    MusicCmdKind::Queue {
-                        query: "http://link".into(),
-                        search: false,
-                    },
-                    MusicCmdKind::Now { amount: Some(10) },
-                    MusicCmdKind::Now { amount: None },
+                    Command::Music(spark_protocol::music::MusicCmd {
+                        command: command.clone(),
+                        index: None,
+                        username: Some("username".into()),
+                    }),
+                    Command::Music(spark_protocol::music::MusicCmd {
+                        command: command.clone(),
+                        index: Some(1),
+                        username: None,
+                    }),
+                    Command::Music(spark_protocol::music::MusicCmd {
+                        command: command.clone(),
+                        index: None,
+                        username: None,
+                    }),
                 ]
-                .into_iter()
-                .flat_map(|command| {
-                    [
-                        Command::Music(spark_protocol::music::MusicCmd {
-                            command: command.clone(),
-                            index: None,
-                            username: Some("username".into()),
-                        }),
-                        Command::Music(spark_protocol::music::MusicCmd {
-                            command: command.clone(),
-                            index: Some(1),
-                            username: None,
-                        }),
-                        Command::Music(spark_protocol::music::MusicCmd {
-                            command: command.clone(),
-                            index: None,
-                            username: None,
-                        }),
-                    ]
-                }),
-            ),
+            }),
+        ),
     );
 
     display(
         [
             SuccessfulResponse::Unit,
             SuccessfulResponse::Version("1.1.1".into()),
+            SuccessfulResponse::Handshake(Capabilities::all()),
+            SuccessfulResponse::ExecOutput(ExecOutput {
+                stdout: "up 2 weeks, 3 days\n".into(),
+                stderr: String::new(),
+                status: Some(0),
+            }),
+            SuccessfulResponse::SystemInfo(SystemInfo {
+                uptime: Duration::from_secs(86400),
+                load_average: LoadAverage {
+                    one: 0.5,
+                    five: 0.25,
+                    fifteen: 0.1,
+                },
+                memory: Memory {
+                    total: 4096,
+                    available: 1024,
+                },
+                disks: vec![Disk {
+                    device: "/dev/sda1".into(),
+                    mount_point: "/".into(),
+                    total: 4096,
+                    available: 1024,
+                }],
+                batteries: vec![Battery {
+                    name: "BAT0".into(),
+                    capacity: 87,
+                    status: "Discharging".into(),
+                }],
+                cpu_temperature: Some(48.3),
+            }),
+            SuccessfulResponse::FileChunk(FileChunk {
+                total_size: 4,
+                checksum: Some(files::checksum(b"data")),
+                data: b"data".to_vec(),
+            }),
+            SuccessfulResponse::Status(DaemonStatus {
+                version: "0.5.21".into(),
+                uptime: Duration::from_secs(3600),
+                ipc_socket_path: "/tmp/spark/socket".into(),
+                persistent_conn: PersistentConnStatus {
+                    connected: true,
+                    since: Some(Duration::from_secs(600)),
+                    reconnects: 1,
+                    last_error: None,
+                },
+                machine_status: MachineStatusStatus {
+                    last_success: Some(Duration::from_secs(30)),
+                    last_status_code: Some(200),
+                    failures: 0,
+                    last_error: None,
+                },
+            }),
+            SuccessfulResponse::Config(serde_json::json!({
+                "backend_domain": "https://blind-eternities.example",
+                "network": { "ssh": 22, "aliases": {} },
+                "default_user": "mendess",
+            })),
         ]
         .into_iter()
         .chain(
@@ -96,6 +224,23 @@ fn main() {
                     current: "current".into(),
                     after: vec!["after".into()],
                 },
+                music::Response::Position {
+                    playback_time: Some(Duration::from_secs(30)),
+                    duration: Duration::from_secs(60),
+                },
+                music::Response::Shuffle { shuffled: true },
+                music::Response::Loop { looping: false },
+                music::Response::Removed { index: 3 },
+                music::Response::Moved { from: 3, to: 1 },
+                music::Response::Cleared,
+                music::Response::Players {
+                    players: vec![music::Player {
+                        index: 0,
+                        owner: Some("username".into()),
+                        title: Some("title".into()),
+                        paused: Some(false),
+                    }],
+                },
             ]
             .map(SuccessfulResponse::MusicResponse),
         )
@@ -108,7 +253,19 @@ fn main() {
             ErrorResponse::ForwardedError("error".into()),
             ErrorResponse::RelayError("error".into()),
             ErrorResponse::RequestFailed("error".into()),
+            ErrorResponse::Unsupported {
+                command: "Music".into(),
+                protocol_version: PROTOCOL_VERSION,
+            },
         ]
         .map(Err::<SuccessfulResponse, _>),
-    )
+    );
+    display([Request {
+        id: 1,
+        command: Command::Version,
+    }]);
+    display([Reply {
+        id: 1,
+        response: Ok(SuccessfulResponse::Version("1.1.1".into())),
+    }]);
 }
@@ -0,0 +1,89 @@
+use std::collections::BTreeSet;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{Command, ErrorResponse};
+
+/// Version of the wire protocol spoken by this build.
+///
+/// Bump this whenever a change to the wire format is not backwards compatible.
+///
+/// - 1: handshake and capabilities.
+/// - 2: [`crate::Request`]s tagged with ids, answered out of order.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The version reported for peers that predate the handshake, and so never advertised one.
+pub const UNKNOWN_PROTOCOL_VERSION: u32 = 0;
+
+/// What a peer advertises during the handshake: the protocol version it speaks and the names of
+/// the [`Command`] variants it is willing to handle.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct Capabilities {
+    pub protocol_version: u32,
+    pub commands: BTreeSet<String>,
+}
+
+impl Capabilities {
+    /// Every command known to this build of the protocol.
+    pub fn all() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            commands: Command::NAMES.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Removes a command from the advertised set.
+    pub fn without(mut self, command: &str) -> Self {
+        self.commands.remove(command);
+        self
+    }
+
+    /// Whether the peer accepts [`crate::Request`]s, and so can have many of them in flight at
+    /// once.
+    pub fn pipelining(&self) -> bool {
+        self.protocol_version >= 2
+    }
+
+    pub fn supports(&self, command: &Command) -> bool {
+        // the handshake itself is always supported, otherwise we couldn't have gotten here
+        matches!(command, Command::Handshake(_)) || self.commands.contains(command.name())
+    }
+
+    pub fn check(&self, command: &Command) -> Result<(), ErrorResponse> {
+        if self.supports(command) {
+            Ok(())
+        } else {
+            Err(ErrorResponse::Unsupported {
+                command: command.name().into(),
+                protocol_version: self.protocol_version,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn all_supports_every_command() {
+        let all = Capabilities::all();
+        for cmd in [Command::Reload, Command::Heartbeat, Command::Version] {
+            assert_eq!(all.check(&cmd), Ok(()), "{cmd:?}");
+        }
+    }
+
+    #[test]
+    fn removed_commands_are_rejected() {
+        let caps = Capabilities::all().without("Reload");
+        assert_eq!(
+            caps.check(&Command::Reload),
+            Err(ErrorResponse::Unsupported {
+                command: "Reload".into(),
+                protocol_version: PROTOCOL_VERSION,
+            })
+        );
+        assert!(caps.supports(&Command::Handshake(Capabilities::all())));
+    }
+}
@@ -1,27 +1,241 @@
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
 use common::net::{ReadJsonLinesExt, RecvError, WriteJsonLinesExt};
+use futures::{
+    Stream,
+    future::{Either, ready},
+    stream,
+};
+use serde::Deserialize;
 use tokio::{
     io::{self, BufReader, BufWriter},
     net::{self, UnixStream},
+    sync::{self, mpsc},
+    task::JoinHandle,
 };
 
-use crate::{Command, Response};
+use crate::{
+    Capabilities, Command, ErrorResponse, Reply, Request, RequestId, Response, SuccessfulResponse,
+    music::Subscription,
+};
 
 use super::socket_path;
 
+/// A line received from the server.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    Reply(Reply),
+    Bare(Response),
+}
+
+#[derive(Debug)]
+struct Waiter {
+    tx: mpsc::UnboundedSender<Response>,
+    /// Subscriptions get every response sent with their id, instead of just the first one.
+    streaming: bool,
+}
+
+impl Waiter {
+    /// Hands over a response, returning whether more are expected.
+    fn deliver(&self, response: Response) -> bool {
+        self.tx.send(response).is_ok() && self.streaming
+    }
+}
+
+/// Requests waiting for their replies.
+#[derive(Debug, Default)]
+struct Pending {
+    by_id: HashMap<RequestId, Waiter>,
+    /// Untagged commands, which the server answers in the order they were sent.
+    in_order: VecDeque<Waiter>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// A connection to a spark. Commands can be sent concurrently, each [`Client::send`] resolves when
+/// its own reply arrives.
 #[derive(Debug)]
 pub struct Client {
-    pub(crate) reader: BufReader<net::unix::OwnedReadHalf>,
-    pub(crate) writer: BufWriter<net::unix::OwnedWriteHalf>,
+    writer: sync::Mutex<BufWriter<net::unix::OwnedWriteHalf>>,
+    pending: Arc<Mutex<Pending>>,
+    next_id: AtomicU64,
+    remote: Option<Capabilities>,
+    _reader: AbortOnDrop,
 }
 
 impl Client {
-    #[inline(always)]
-    pub async fn send(&mut self, cmd: &Command) -> Result<Option<Response>, RecvError> {
-        self.writer.send(cmd).await?;
-        self.reader.recv().await
+    /// Sends a command, rejecting it locally if the server advertised that it doesn't support it.
+    ///
+    /// Returns `None` if the connection was closed before the reply arrived.
+    pub async fn send(&self, cmd: &Command) -> Result<Option<Response>, RecvError> {
+        if let Some(remote) = &self.remote
+            && let Err(e) = remote.check(cmd)
+        {
+            return Ok(Some(Err(e)));
+        }
+        self.send_unchecked(cmd).await
+    }
+
+    async fn send_unchecked(&self, cmd: &Command) -> Result<Option<Response>, RecvError> {
+        match self.request(cmd, false).await? {
+            Some(mut replies) => Ok(replies.recv().await),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the command and registers where its replies should go. Returns `None` if the
+    /// connection is already closed.
+    async fn request(
+        &self,
+        cmd: &Command,
+        streaming: bool,
+    ) -> io::Result<Option<mpsc::UnboundedReceiver<Response>>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let waiter = Waiter { tx, streaming };
+        // holding the writer while registering keeps the in order queue in the order the
+        // commands were written
+        let mut writer = self.writer.lock().await;
+        let pipelining = self.remote.as_ref().is_some_and(Capabilities::pipelining);
+        let id = pipelining.then(|| self.next_id.fetch_add(1, Ordering::Relaxed));
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed {
+                return Ok(None);
+            }
+            match id {
+                Some(id) => {
+                    pending.by_id.insert(id, waiter);
+                }
+                None => pending.in_order.push_back(waiter),
+            }
+        }
+        match id {
+            Some(id) => {
+                writer
+                    .send(Request {
+                        id,
+                        command: cmd.clone(),
+                    })
+                    .await?
+            }
+            None => writer.send(cmd).await?,
+        }
+        Ok(Some(rx))
+    }
+
+    /// Exchanges capabilities with the server.
+    ///
+    /// Returns `None` if the server predates the handshake, in which case every command is sent
+    /// as is.
+    pub async fn handshake(&mut self) -> Result<Option<&Capabilities>, RecvError> {
+        let response = self
+            .send_unchecked(&Command::Handshake(Capabilities::all()))
+            .await?;
+        self.remote = match response {
+            Some(Ok(SuccessfulResponse::Handshake(remote))) => {
+                tracing::debug!(?remote, "handshake complete");
+                Some(remote)
+            }
+            response => {
+                tracing::debug!(?response, "server does not support handshakes");
+                None
+            }
+        };
+        Ok(self.remote.as_ref())
+    }
+
+    /// Subscribes to music player events. The subscription lasts as long as the connection, so
+    /// the client is consumed; dropping the stream unsubscribes.
+    pub async fn subscribe(
+        self,
+        subscription: Subscription,
+    ) -> Result<impl Stream<Item = Response> + Send, RecvError> {
+        let cmd = Command::Subscribe(subscription);
+        if let Some(remote) = &self.remote
+            && let Err(e) = remote.check(&cmd)
+        {
+            return Ok(Either::Left(stream::once(ready(Err(e)))));
+        }
+        let events = self.request(&cmd, true).await?;
+        Ok(Either::Right(stream::unfold(
+            (self, events),
+            |(client, events)| async move {
+                let mut events = events?;
+                let event = events.recv().await?;
+                // the server won't send anything else after refusing the subscription
+                let refused = matches!(
+                    event,
+                    Err(ErrorResponse::Unsupported { .. }
+                        | ErrorResponse::DeserializingCommand(_)
+                        | ErrorResponse::PermissionDenied { .. })
+                );
+                Some((event, (client, (!refused).then_some(events))))
+            },
+        )))
+    }
+
+    pub fn remote_capabilities(&self) -> Option<&Capabilities> {
+        self.remote.as_ref()
+    }
+}
+
+/// Routes every reply to whoever is waiting for it, until the server hangs up.
+async fn dispatch(mut reader: BufReader<net::unix::OwnedReadHalf>, pending: Arc<Mutex<Pending>>) {
+    loop {
+        let incoming = match reader.recv::<Incoming>().await {
+            Ok(Some(incoming)) => incoming,
+            Ok(None) => break,
+            Err(RecvError::Serde(e)) => {
+                tracing::warn!(error = ?e, "ignoring malformed reply");
+                continue;
+            }
+            Err(RecvError::Io(e)) => {
+                tracing::error!(error = ?e, "failed to receive reply");
+                break;
+            }
+        };
+        let mut pending = pending.lock().unwrap();
+        match incoming {
+            Incoming::Reply(Reply { id, response }) => {
+                let more = pending
+                    .by_id
+                    .get(&id)
+                    .is_some_and(|waiter| waiter.deliver(response));
+                if !more {
+                    pending.by_id.remove(&id);
+                }
+            }
+            Incoming::Bare(response) => match pending.in_order.front() {
+                Some(waiter) => {
+                    if !waiter.deliver(response) {
+                        pending.in_order.pop_front();
+                    }
+                }
+                None => tracing::warn!(?response, "ignoring reply nobody asked for"),
+            },
+        }
     }
+    // dropping the waiters wakes everyone up
+    *pending.lock().unwrap() = Pending {
+        closed: true,
+        ..Default::default()
+    };
 }
 
 #[derive(Default, Debug)]
@@ -44,22 +258,29 @@ impl ClientBuilder {
             Some(p) => UnixStream::connect(p).await?,
             None => UnixStream::connect(socket_path().await?).await?,
         };
-        Ok(Client::from(socket))
+        let mut client = Client::from(socket);
+        client.handshake().await?;
+        Ok(client)
     }
 }
 
 impl From<UnixStream> for Client {
     fn from(s: UnixStream) -> Self {
         let (r, w) = s.into_split();
+        let pending = Arc::default();
         Self {
-            reader: BufReader::new(r),
-            writer: BufWriter::new(w),
+            writer: sync::Mutex::new(BufWriter::new(w)),
+            _reader: AbortOnDrop(tokio::spawn(dispatch(
+                BufReader::new(r),
+                Arc::clone(&pending),
+            ))),
+            pending,
+            next_id: AtomicU64::new(0),
+            remote: None,
         }
     }
 }
 
 pub async fn send(cmd: &Command) -> Result<Option<Response>, RecvError> {
-    let path = socket_path().await?;
-    let socket = UnixStream::connect(path).await?;
-    Client::from(socket).send(cmd).await
+    ClientBuilder::new().build().await?.send(cmd).await
 }
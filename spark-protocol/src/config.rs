@@ -0,0 +1,26 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Changes the config of a running spark.
+///
+/// The patch is a JSON merge patch (RFC 7396) of the spark's config file: objects are merged
+/// key by key and `null` removes a setting. Only the settings that can be applied without
+/// reloading may be changed this way, patches touching anything else are refused.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::Parser))]
+pub struct SetConfig {
+    /// e.g. '{"network": {"ssh": 2222}, "default_user": null}'
+    #[cfg_attr(feature = "clap", arg(value_parser = parse_json))]
+    pub patch: serde_json::Value,
+}
+
+#[cfg(feature = "clap")]
+fn parse_json(s: &str) -> serde_json::Result<serde_json::Value> {
+    serde_json::from_str(s)
+}
+
+impl From<SetConfig> for super::Command {
+    fn from(set: SetConfig) -> Self {
+        Self::SetConfig(set)
+    }
+}
@@ -0,0 +1,53 @@
+use std::fmt;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Runs a program on the remote machine. Only programs in the remote spark's allow-list are run.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::Parser))]
+pub struct Exec {
+    pub program: String,
+    #[cfg_attr(
+        feature = "clap",
+        arg(trailing_var_arg = true, allow_hyphen_values = true)
+    )]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+}
+
+impl From<Exec> for super::Command {
+    fn from(exec: Exec) -> Self {
+        Self::Exec(exec)
+    }
+}
+
+/// What an [`Exec`]ed program, or a [`super::Command::Custom`] one, left behind.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct ExecOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// The exit code, `None` if the program was killed by a signal.
+    pub status: Option<i32>,
+}
+
+impl fmt::Display for ExecOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut last = self.stdout.as_str();
+        f.write_str(&self.stdout)?;
+        if !self.stderr.is_empty() {
+            if !last.is_empty() && !last.ends_with('\n') {
+                writeln!(f)?;
+            }
+            write!(f, "stderr:\n{}", self.stderr)?;
+            last = &self.stderr;
+        }
+        if !last.is_empty() && !last.ends_with('\n') {
+            writeln!(f)?;
+        }
+        match self.status {
+            Some(code) => write!(f, "exit status: {code}"),
+            None => f.write_str("killed by a signal"),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Copying files to and from machines that can only be reached through the backend's relay.
+//!
+//! Files are sent one [`CHUNK_SIZE`] chunk per command, in order, and checked against an md5
+//! [`checksum`] of the whole file once the last chunk arrives.
+
+use std::{fmt, path::PathBuf};
+
+use md5::{Digest as _, Md5};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Largest amount of file contents sent in a single command or response.
+///
+/// socket.io messages are limited to 100kB by default and base64 inflates the data by a third,
+/// so this leaves some room for the rest of the message.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest file that can be transferred.
+pub const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The checksum of a whole file, as sent in [`PutFile::checksum`] and [`FileChunk::checksum`].
+pub fn checksum(contents: &[u8]) -> String {
+    format!("{:x}", Md5::digest(contents))
+}
+
+/// Writes a chunk of a file on the remote machine. Chunks must be sent in order, starting at
+/// offset 0. The file is only put in place once the last chunk arrives and the checksum matches.
+///
+/// Relative paths are relative to the home directory of the remote spark.
+#[derive(Clone, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct PutFile {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub total_size: u64,
+    /// Checksum of the whole file.
+    pub checksum: String,
+    #[serde(with = "as_base64")]
+    #[schemars(with = "String", description = "Base64 encoded.")]
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for PutFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PutFile")
+            .field("path", &self.path)
+            .field("offset", &self.offset)
+            .field("total_size", &self.total_size)
+            .field("checksum", &self.checksum)
+            .field("data", &format_args!("{} bytes", self.data.len()))
+            .finish()
+    }
+}
+
+impl From<PutFile> for super::Command {
+    fn from(put: PutFile) -> Self {
+        Self::PutFile(put)
+    }
+}
+
+/// Reads up to [`CHUNK_SIZE`] bytes of a file on the remote machine, starting at `offset`.
+///
+/// Relative paths are relative to the home directory of the remote spark.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct GetFile {
+    pub path: PathBuf,
+    pub offset: u64,
+}
+
+impl From<GetFile> for super::Command {
+    fn from(get: GetFile) -> Self {
+        Self::GetFile(get)
+    }
+}
+
+/// A chunk of a file, in response to [`GetFile`].
+#[derive(Clone, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct FileChunk {
+    pub total_size: u64,
+    /// Checksum of the whole file, only sent with the last chunk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    #[serde(with = "as_base64")]
+    #[schemars(with = "String", description = "Base64 encoded.")]
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for FileChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileChunk")
+            .field("total_size", &self.total_size)
+            .field("checksum", &self.checksum)
+            .field("data", &format_args!("{} bytes", self.data.len()))
+            .finish()
+    }
+}
+
+mod as_base64 {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer, de::Error as _};
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        STANDARD
+            .decode(String::deserialize(d)?)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunks_fit_in_a_socket_io_message() {
+        let put = crate::Command::PutFile(PutFile {
+            path: "/a/reasonably/long/path/to/some/config/file.toml".into(),
+            offset: MAX_FILE_SIZE,
+            total_size: MAX_FILE_SIZE,
+            checksum: checksum(b""),
+            data: vec![u8::MAX; CHUNK_SIZE],
+        });
+        assert!(serde_json::to_string(&put).unwrap().len() < 100_000);
+
+        let reply = crate::Reply {
+            id: u64::MAX,
+            response: Ok(crate::SuccessfulResponse::FileChunk(FileChunk {
+                total_size: MAX_FILE_SIZE,
+                checksum: Some(checksum(b"")),
+                data: vec![u8::MAX; CHUNK_SIZE],
+            })),
+        };
+        assert!(serde_json::to_string(&reply).unwrap().len() < 100_000);
+    }
+
+    #[test]
+    fn data_round_trips() {
+        let chunk = FileChunk {
+            total_size: 3,
+            checksum: None,
+            data: vec![0, 1, 255],
+        };
+        let json = serde_json::to_string(&chunk).unwrap();
+        assert_eq!(serde_json::from_str::<FileChunk>(&json).unwrap(), chunk);
+    }
+}
@@ -1,16 +1,26 @@
+pub mod capabilities;
 pub mod client;
+pub mod config;
+pub mod exec;
+pub mod files;
 pub mod music;
+pub mod relay;
+pub mod schema;
 pub mod server;
+pub mod status;
+pub mod system_info;
 
-use std::{fmt, path::PathBuf};
+use std::{fmt, path::PathBuf, time::Duration};
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tokio::io;
 
+pub use capabilities::{Capabilities, PROTOCOL_VERSION, UNKNOWN_PROTOCOL_VERSION};
 pub use common::net::RecvError;
 
 /// Command to send to a spark instance.
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
 #[cfg_attr(feature = "clap", derive(clap::Subcommand))]
 pub enum Command {
     /// Reload the spark instance
@@ -21,6 +31,99 @@ pub enum Command {
     Music(music::MusicCmd),
     /// Returns the running version
     Version,
+    /// Exchange protocol versions and supported commands with the remote.
+    #[cfg_attr(feature = "clap", command(skip))]
+    Handshake(Capabilities),
+    /// Keep the connection open and receive music player events as they happen.
+    Subscribe(music::Subscription),
+    /// Run an allow-listed program and get back its output.
+    Exec(exec::Exec),
+    /// Write a chunk of a file.
+    #[cfg_attr(feature = "clap", command(skip))]
+    PutFile(files::PutFile),
+    /// Read a chunk of a file.
+    #[cfg_attr(feature = "clap", command(skip))]
+    GetFile(files::GetFile),
+    /// Uptime, load, memory, disk usage and temperatures of the machine.
+    SystemInfo,
+    /// Download the build of this version published on the backend and reload into it.
+    Update { version: String },
+    /// The config the spark is running with, without its token.
+    GetConfig,
+    /// Change the config of the spark without reloading it.
+    SetConfig(config::SetConfig),
+    /// How the background tasks of the daemon are doing.
+    Status,
+    /// Run one of the commands declared in the remote spark's config and get back its output.
+    Custom {
+        name: String,
+        #[cfg_attr(
+            feature = "clap",
+            arg(trailing_var_arg = true, allow_hyphen_values = true)
+        )]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        args: Vec<String>,
+    },
+}
+
+impl Command {
+    /// The names of every command variant, as they appear on the wire.
+    pub const NAMES: &[&str] = &[
+        "Reload",
+        "Heartbeat",
+        "Music",
+        "Version",
+        "Handshake",
+        "Subscribe",
+        "Exec",
+        "PutFile",
+        "GetFile",
+        "SystemInfo",
+        "Update",
+        "GetConfig",
+        "SetConfig",
+        "Status",
+        "Custom",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Reload => "Reload",
+            Self::Heartbeat => "Heartbeat",
+            Self::Music(_) => "Music",
+            Self::Version => "Version",
+            Self::Handshake(_) => "Handshake",
+            Self::Subscribe(_) => "Subscribe",
+            Self::Exec(_) => "Exec",
+            Self::PutFile(_) => "PutFile",
+            Self::GetFile(_) => "GetFile",
+            Self::SystemInfo => "SystemInfo",
+            Self::Update { .. } => "Update",
+            Self::GetConfig => "GetConfig",
+            Self::SetConfig(_) => "SetConfig",
+            Self::Status => "Status",
+            Self::Custom { .. } => "Custom",
+        }
+    }
+}
+
+pub type RequestId = u64;
+
+/// A [`Command`] tagged with an id picked by the client, so that many commands can be in flight
+/// on the same connection. The [`Reply`] carries the same id.
+///
+/// Clients that predate this send bare [`Command`]s, which are answered in order with bare
+/// [`Response`]s.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct Request {
+    pub id: RequestId,
+    pub command: Command,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct Reply {
+    pub id: RequestId,
+    pub response: Response,
 }
 
 // /// Hits the spark instance in a remote machine
@@ -34,12 +137,19 @@ pub enum Command {
 
 pub type Response = Result<SuccessfulResponse, ErrorResponse>;
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
 #[allow(clippy::large_enum_variant)]
 pub enum SuccessfulResponse {
     Unit,
     Version(String),
     MusicResponse(music::Response),
+    Handshake(Capabilities),
+    ExecOutput(exec::ExecOutput),
+    FileChunk(files::FileChunk),
+    SystemInfo(system_info::SystemInfo),
+    /// A spark's config, as json.
+    Config(serde_json::Value),
+    Status(status::DaemonStatus),
 }
 
 impl From<music::Response> for SuccessfulResponse {
@@ -92,12 +202,76 @@ impl fmt::Display for ResponseDisplay<'_> {
                         )?;
                         msg
                     }
+                    ErrorResponse::Unsupported {
+                        command,
+                        protocol_version,
+                    } => {
+                        return write!(
+                            f,
+                            "remote spark (protocol version {protocol_version}) does not support the {command} command"
+                        );
+                    }
+                    ErrorResponse::NoPlayer { index, username } => {
+                        return write!(f, "{} is not running", PlayerDisplay { index, username });
+                    }
+                    ErrorResponse::PlayerTimeout {
+                        index,
+                        username,
+                        timeout,
+                    } => {
+                        return write!(
+                            f,
+                            "{} did not respond after {}s",
+                            PlayerDisplay { index, username },
+                            timeout.as_secs()
+                        );
+                    }
+                    ErrorResponse::PlaylistNotFound { reason } => {
+                        writeln!(f, "remote spark could not find the playlist")?;
+                        reason
+                    }
+                    ErrorResponse::SearchAmbiguous { query, matches } => {
+                        write!(f, "too many songs match {query:?}:")?;
+                        for m in matches {
+                            write!(f, "\n  {m}")?;
+                        }
+                        return Ok(());
+                    }
+                    ErrorResponse::PermissionDenied { command, uid } => {
+                        return write!(
+                            f,
+                            "user {uid} is not allowed to send the {command} command"
+                        );
+                    }
                 };
                 write!(f, " -> {msg}")
             }
             Ok(response) => match response {
                 SuccessfulResponse::Unit => f.write_str("success"),
                 SuccessfulResponse::Version(version) => f.write_str(version),
+                SuccessfulResponse::ExecOutput(output) => write!(f, "{output}"),
+                SuccessfulResponse::SystemInfo(info) => write!(f, "{info}"),
+                SuccessfulResponse::Status(status) => write!(f, "{status}"),
+                SuccessfulResponse::Config(config) => f.write_str(
+                    &serde_json::to_string_pretty(config).expect("json values always serialize"),
+                ),
+                SuccessfulResponse::FileChunk(chunk) => write!(
+                    f,
+                    "{} bytes of a {} byte file",
+                    chunk.data.len(),
+                    chunk.total_size
+                ),
+                SuccessfulResponse::Handshake(Capabilities {
+                    protocol_version,
+                    commands,
+                }) => {
+                    writeln!(f, "protocol version: {protocol_version}")?;
+                    write!(f, "supported commands:")?;
+                    for c in commands {
+                        write!(f, " {c}")?;
+                    }
+                    Ok(())
+                }
                 SuccessfulResponse::MusicResponse(music_resp) => {
                     use music::Response::*;
                     match music_resp {
@@ -154,6 +328,46 @@ impl fmt::Display for ResponseDisplay<'_> {
                             }
                             Ok(())
                         }
+                        Position {
+                            playback_time,
+                            duration,
+                        } => write!(
+                            f,
+                            "at {}s of {}s",
+                            playback_time.unwrap_or_default().as_secs(),
+                            duration.as_secs()
+                        ),
+                        Shuffle { shuffled } => f.write_str(if *shuffled {
+                            "shuffling"
+                        } else {
+                            "not shuffling"
+                        }),
+                        Loop { looping } => {
+                            f.write_str(if *looping { "looping" } else { "not looping" })
+                        }
+                        Removed { index } => write!(f, "removed song {index}"),
+                        Moved { from, to } => write!(f, "moved song {from} to {to}"),
+                        Cleared => f.write_str("queue cleared"),
+                        Players { players } => {
+                            if players.is_empty() {
+                                return f.write_str("no players running");
+                            }
+                            for (i, player) in players.iter().enumerate() {
+                                if i > 0 {
+                                    writeln!(f)?;
+                                }
+                                write!(f, "{}", player.index)?;
+                                if let Some(owner) = &player.owner {
+                                    write!(f, " ({owner})")?;
+                                }
+                                match (&player.title, player.paused) {
+                                    (Some(title), Some(true)) => write!(f, ": {title} [paused]")?,
+                                    (Some(title), _) => write!(f, ": {title}")?,
+                                    (None, _) => f.write_str(": idle")?,
+                                }
+                            }
+                            Ok(())
+                        }
                     }
                 }
             },
@@ -161,7 +375,7 @@ impl fmt::Display for ResponseDisplay<'_> {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ErrorResponse {
     /// The command could not be understood by the remote spark
     DeserializingCommand(String),
@@ -174,6 +388,47 @@ pub enum ErrorResponse {
     /// The relay (blind-eternities) encountered an error when receiving the response from
     /// the remote spark.
     RelayError(String),
+    /// The remote spark doesn't know or doesn't handle this command.
+    Unsupported {
+        command: String,
+        protocol_version: u32,
+    },
+    /// No music player is running that matches the command.
+    NoPlayer {
+        index: Option<usize>,
+        username: Option<String>,
+    },
+    /// The music player didn't respond in time.
+    PlayerTimeout {
+        index: Option<usize>,
+        username: Option<String>,
+        timeout: Duration,
+    },
+    /// The remote spark has no playlist to search in.
+    PlaylistNotFound { reason: String },
+    /// A search matched more than one song, the names of the songs it matched.
+    SearchAmbiguous { query: String, matches: Vec<String> },
+    /// The local user that sent the command isn't allowed to.
+    PermissionDenied { command: String, uid: u32 },
+}
+
+/// Which player the command was aimed at, as part of an error message.
+struct PlayerDisplay<'s> {
+    index: &'s Option<usize>,
+    username: &'s Option<String>,
+}
+
+impl fmt::Display for PlayerDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.index {
+            Some(i) => write!(f, "player {i}")?,
+            None => f.write_str("the current player")?,
+        }
+        if let Some(username) = self.username {
+            write!(f, " of {username}")?;
+        }
+        Ok(())
+    }
 }
 
 async fn socket_path() -> io::Result<PathBuf> {
@@ -207,7 +462,7 @@ mod test {
         let p = spawn_server();
         tokio::time::sleep(Duration::from_secs(1)).await;
 
-        let mut c = client::Client::from(UnixStream::connect(&p).await.unwrap());
+        let c = client::Client::from(UnixStream::connect(&p).await.unwrap());
         for i in 0..10 {
             let response = c
                 .send(&Command::Reload)
@@ -218,12 +473,247 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn unsupported_commands_are_rejected_after_handshake() {
+        let p = spawn_server_with(Capabilities::all().without("Reload"));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let mut c = client::Client::from(UnixStream::connect(&p).await.unwrap());
+        let remote = c.handshake().await.unwrap().cloned();
+        assert_eq!(
+            remote,
+            // subscriptions are only advertised when the server can serve them
+            Some(Capabilities::all().without("Reload").without("Subscribe"))
+        );
+
+        let response = c
+            .send(&Command::Reload)
+            .await
+            .unwrap()
+            .expect("end of file");
+        assert_eq!(
+            Err(ErrorResponse::Unsupported {
+                command: "Reload".into(),
+                protocol_version: PROTOCOL_VERSION
+            }),
+            response
+        );
+        let response = c
+            .send(&Command::Version)
+            .await
+            .unwrap()
+            .expect("end of file");
+        assert_eq!(Ok(SuccessfulResponse::Unit), response);
+    }
+
+    #[tokio::test]
+    async fn the_authorizer_sees_who_sent_the_command() {
+        let path = NamedTempFile::new().unwrap().into_temp_path();
+        let to_path_buf = path.to_path_buf();
+        tokio::spawn(async move {
+            server::ServerBuilder::new()
+                .with_path(to_path_buf)
+                .with_authorizer(|peer, cmd| match cmd {
+                    Command::Reload => Err(ErrorResponse::PermissionDenied {
+                        command: cmd.name().into(),
+                        uid: peer.uid(),
+                    }),
+                    _ => Ok(()),
+                })
+                .serve(|_| async { Ok(SuccessfulResponse::Unit) })
+                .await
+                .unwrap()
+                .await
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let mut c = client::Client::from(UnixStream::connect(&path).await.unwrap());
+        assert!(c.handshake().await.unwrap().is_some());
+        let response = c
+            .send(&Command::Reload)
+            .await
+            .unwrap()
+            .expect("end of file");
+        assert_eq!(
+            Err(ErrorResponse::PermissionDenied {
+                command: "Reload".into(),
+                // the socket was created by this process
+                uid: std::os::unix::fs::MetadataExt::uid(&std::fs::metadata(&path).unwrap()),
+            }),
+            response
+        );
+        let response = c
+            .send(&Command::Version)
+            .await
+            .unwrap()
+            .expect("end of file");
+        assert_eq!(Ok(SuccessfulResponse::Unit), response);
+    }
+
+    #[tokio::test]
+    async fn subscriptions_stream_events_until_they_run_out() {
+        use futures::{StreamExt, stream};
+
+        let path = NamedTempFile::new().unwrap().into_temp_path();
+        let to_path_buf = path.to_path_buf();
+        tokio::spawn(async move {
+            server::ServerBuilder::new()
+                .with_path(to_path_buf)
+                .with_subscriptions(|_| {
+                    stream::iter([false, true, false])
+                        .map(|paused| Ok(music::Response::PlayState { paused }.into()))
+                })
+                .serve(|_| async { Ok(SuccessfulResponse::Unit) })
+                .await
+                .unwrap()
+                .await
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let mut c = client::Client::from(UnixStream::connect(&path).await.unwrap());
+        c.handshake().await.unwrap();
+        let events = c
+            .subscribe(music::Subscription {
+                events: vec![],
+                index: None,
+                username: None,
+            })
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            [false, true, false]
+                .map(|paused| Ok(music::Response::PlayState { paused }.into()))
+                .to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribing_without_a_subscriber_is_unsupported() {
+        use futures::StreamExt;
+
+        let p = spawn_server();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let c = client::Client::from(UnixStream::connect(&p).await.unwrap());
+        let events = c
+            .subscribe(music::Subscription {
+                events: vec![],
+                index: None,
+                username: None,
+            })
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![Err(ErrorResponse::Unsupported {
+                command: "Subscribe".into(),
+                protocol_version: PROTOCOL_VERSION,
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn tagged_requests_are_answered_out_of_order() {
+        let path = NamedTempFile::new().unwrap().into_temp_path();
+        let to_path_buf = path.to_path_buf();
+        tokio::spawn(async move {
+            server::ServerBuilder::new()
+                .with_path(to_path_buf)
+                .serve(|cmd| async move {
+                    if cmd == Command::Version {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        Ok(SuccessfulResponse::Version("slow".into()))
+                    } else {
+                        Ok(SuccessfulResponse::Unit)
+                    }
+                })
+                .await
+                .unwrap()
+                .await
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let mut c = client::Client::from(UnixStream::connect(&path).await.unwrap());
+        assert!(c.handshake().await.unwrap().unwrap().pipelining());
+
+        let slow = c.send(&Command::Version);
+        let fast = c.send(&Command::Reload);
+        tokio::pin!(slow);
+        tokio::select! {
+            response = fast => {
+                assert_eq!(response.unwrap(), Some(Ok(SuccessfulResponse::Unit)));
+            }
+            _ = &mut slow => panic!("the slow command was answered first"),
+        }
+        assert_eq!(
+            slow.await.unwrap(),
+            Some(Ok(SuccessfulResponse::Version("slow".into())))
+        );
+    }
+
+    #[test]
+    fn player_errors_name_the_player() {
+        let no_player: Response = Err(ErrorResponse::NoPlayer {
+            index: Some(1),
+            username: Some("mendess".into()),
+        });
+        assert_eq!(
+            no_player.display().to_string(),
+            "Error: player 1 of mendess is not running"
+        );
+        let timeout: Response = Err(ErrorResponse::PlayerTimeout {
+            index: None,
+            username: None,
+            timeout: Duration::from_secs(30),
+        });
+        assert_eq!(
+            timeout.display().to_string(),
+            "Error: the current player did not respond after 30s"
+        );
+    }
+
+    #[test]
+    fn players_are_listed_one_per_line() {
+        let players: Response = Ok(SuccessfulResponse::MusicResponse(
+            music::Response::Players {
+                players: vec![
+                    music::Player {
+                        index: 0,
+                        owner: Some("mendess".into()),
+                        title: Some("song".into()),
+                        paused: Some(true),
+                    },
+                    music::Player {
+                        index: 1,
+                        owner: None,
+                        title: None,
+                        paused: None,
+                    },
+                ],
+            },
+        ));
+        assert_eq!(
+            players.display().to_string(),
+            "0 (mendess): song [paused]\n1: idle"
+        );
+    }
+
     fn spawn_server() -> TempPath {
+        spawn_server_with(Capabilities::all())
+    }
+
+    fn spawn_server_with(capabilities: Capabilities) -> TempPath {
         let path = NamedTempFile::new().unwrap().into_temp_path();
         let to_path_buf = path.to_path_buf();
         tokio::spawn(async move {
             server::ServerBuilder::new()
                 .with_path(to_path_buf)
+                .with_capabilities(capabilities)
                 .serve(|_| async { Ok(SuccessfulResponse::Unit) })
                 .await
                 .unwrap()
@@ -1,8 +1,11 @@
+use std::time::Duration;
+
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 type PlayerIdx = usize;
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
 #[cfg_attr(feature = "clap", derive(clap::Parser))]
 pub struct MusicCmd {
     #[cfg_attr(feature = "clap", command(subcommand))]
@@ -21,7 +24,7 @@ impl From<MusicCmd> for super::Command {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "clap", derive(clap::Subcommand))]
 pub enum MusicCmdKind {
     Frwd,
@@ -41,6 +44,31 @@ pub enum MusicCmdKind {
         #[serde(skip_serializing_if = "Option::is_none")]
         amount: Option<usize>,
     },
+    /// Seek to a position in the current song.
+    Seek {
+        seconds: u64,
+    },
+    /// Toggle playing the queue in a random order.
+    Shuffle,
+    /// Toggle starting the queue over once it ends.
+    Loop,
+    /// Remove the song at an index of the queue.
+    Remove {
+        index: usize,
+    },
+    /// Move the song at an index of the queue to another one.
+    Move {
+        from: usize,
+        to: usize,
+    },
+    /// Remove every song from the queue, except the one playing.
+    Clear,
+    /// Play the song at an index of the queue.
+    Jump {
+        index: usize,
+    },
+    /// List the players running on the machine, only the ones of `username` if it's given.
+    ListPlayers,
 }
 
 impl From<MusicCmdKind> for super::Command {
@@ -53,7 +81,57 @@ impl From<MusicCmdKind> for super::Command {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// Player events a client can subscribe to. Each one is delivered as the matching [`Response`].
+#[derive(
+    Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum EventKind {
+    Title,
+    PlayState,
+    Volume,
+}
+
+impl EventKind {
+    pub fn of(response: &Response) -> Option<Self> {
+        match response {
+            Response::Title { .. } => Some(Self::Title),
+            Response::PlayState { .. } => Some(Self::PlayState),
+            Response::Volume { .. } => Some(Self::Volume),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::Parser))]
+pub struct Subscription {
+    /// Which events to receive, all of them if none are given.
+    #[cfg_attr(feature = "clap", arg(short, long, value_enum))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<EventKind>,
+    #[cfg_attr(feature = "clap", arg(short, long))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<PlayerIdx>,
+    #[cfg_attr(feature = "clap", arg(short, long))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+}
+
+impl Subscription {
+    pub fn wants(&self, response: &Response) -> bool {
+        EventKind::of(response)
+            .is_some_and(|kind| self.events.is_empty() || self.events.contains(&kind))
+    }
+}
+
+impl From<Subscription> for super::Command {
+    fn from(subscription: Subscription) -> Self {
+        Self::Subscribe(subscription)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
 pub enum Response {
     Title {
         title: String,
@@ -66,6 +144,7 @@ pub enum Response {
     },
     Current {
         #[serde(flatten)]
+        #[schemars(with = "crate::schema::Current")]
         current: Current,
     },
     QueueSummary {
@@ -78,6 +157,39 @@ pub enum Response {
         current: String,
         after: Vec<String>,
     },
+    Position {
+        playback_time: Option<Duration>,
+        duration: Duration,
+    },
+    Shuffle {
+        shuffled: bool,
+    },
+    Loop {
+        looping: bool,
+    },
+    Removed {
+        index: usize,
+    },
+    Moved {
+        from: usize,
+        to: usize,
+    },
+    Cleared,
+    Players {
+        players: Vec<Player>,
+    },
+}
+
+/// A music player running on a machine, as listed by [`MusicCmdKind::ListPlayers`].
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct Player {
+    /// What to set [`MusicCmd::index`] to, to control this player.
+    pub index: PlayerIdx,
+    /// The user the player is linked to, only known when listing the players of a user.
+    pub owner: Option<String>,
+    /// `None` if the player isn't playing anything.
+    pub title: Option<String>,
+    pub paused: Option<bool>,
 }
 
 pub use mlib::queue::Current;
@@ -0,0 +1,137 @@
+//! Envelopes used by blind-eternities to relay subscriptions over the persistent connection.
+//!
+//! A single socket can carry many subscriptions at once, so every message is tagged with the id
+//! the relay picked when it subscribed.
+//!
+//! It also holds what's needed to [`Broadcast`] a command to many sparks at once, or to have one
+//! [`Queued`] until its spark connects.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use common::domain::Hostname;
+use serde::{Deserialize, Serialize};
+
+use crate::{Command, Response, music::Subscription};
+
+pub type SubscriptionId = u64;
+
+/// Sent by the relay to start a subscription.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Subscribe {
+    pub id: SubscriptionId,
+    pub subscription: Subscription,
+}
+
+/// Sent by the relay when nobody is listening to a subscription anymore.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Unsubscribe {
+    pub id: SubscriptionId,
+}
+
+/// Sent by the spark for every event of a subscription.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Event {
+    pub id: SubscriptionId,
+    pub response: Response,
+}
+
+/// Which of the connected sparks a [`Broadcast`] is sent to.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Targets {
+    All,
+    /// Hostnames, which may be globs like `pi*`.
+    Hosts(Vec<String>),
+    /// Sparks that were configured with every one of these tags.
+    Tags(BTreeSet<String>),
+}
+
+impl Targets {
+    pub fn matches(&self, hostname: &Hostname, tags: &BTreeSet<String>) -> bool {
+        match self {
+            Self::All => true,
+            Self::Hosts(hosts) => hosts.iter().any(|host| {
+                glob::Pattern::new(host)
+                    .map(|pattern| pattern.matches(hostname.as_ref()))
+                    .unwrap_or_else(|_| host == hostname.as_ref())
+            }),
+            Self::Tags(wanted) => wanted.is_subset(tags),
+        }
+    }
+}
+
+/// Sends a command to many sparks at once, answered with a [`BroadcastResponse`].
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Broadcast {
+    pub targets: Targets,
+    pub command: Command,
+    /// How long to wait for each spark, the relay picks a default if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+}
+
+/// What each of the sparks that matched a [`Broadcast`] replied. Sparks that didn't reply in time
+/// get a [`crate::ErrorResponse::RelayError`].
+pub type BroadcastResponse = BTreeMap<Hostname, Response>;
+
+pub type QueuedId = i64;
+
+/// Replied instead of a [`Response`] when a command was sent to a spark that isn't connected and
+/// the caller asked for it to be delivered once it is.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Queued {
+    pub id: QueuedId,
+    /// How long the relay waits for the spark to connect before giving up on the command.
+    pub expires_in: Duration,
+}
+
+/// How far along the delivery of a [`Queued`] command is.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    /// The spark hasn't connected yet.
+    Pending {
+        expires_in: Duration,
+    },
+    /// The spark connected and was sent the command, but hasn't replied yet.
+    Sent,
+    Delivered {
+        response: Box<Response>,
+    },
+    /// The spark didn't connect in time, so it was never sent the command.
+    Expired,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hostname(h: &str) -> Hostname {
+        h.parse().unwrap()
+    }
+
+    #[test]
+    fn hosts_can_be_globs() {
+        let targets = Targets::Hosts(vec!["pi*".into(), "mirrodin".into()]);
+        let tags = BTreeSet::new();
+        assert!(targets.matches(&hostname("pi0"), &tags));
+        assert!(targets.matches(&hostname("pi4"), &tags));
+        assert!(targets.matches(&hostname("mirrodin"), &tags));
+        assert!(!targets.matches(&hostname("kaladesh"), &tags));
+    }
+
+    #[test]
+    fn every_tag_must_match() {
+        let targets = Targets::Tags(["speakers".into(), "living-room".into()].into());
+        let host = hostname("pi0");
+        assert!(targets.matches(&host, &["speakers".into(), "living-room".into()].into()));
+        assert!(targets.matches(
+            &host,
+            &["speakers".into(), "living-room".into(), "arm".into()].into()
+        ));
+        assert!(!targets.matches(&host, &["speakers".into()].into()));
+    }
+}
@@ -0,0 +1,120 @@
+//! JSON Schemas of the wire format, for clients that aren't written in rust.
+//!
+//! The schemas are committed in the `schema` directory of this crate and regenerated with
+//! `cargo run -p spark-protocol --bin schema`.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use schemars::{JsonSchema, Schema, schema_for};
+use serde::Serialize;
+
+use crate::{Command, ErrorResponse, Response, music};
+
+/// Every exported schema, by name.
+pub fn all() -> [(&'static str, Schema); 5] {
+    [
+        ("Command", schema_for!(Command)),
+        ("Response", schema_for!(Response)),
+        ("MusicCmdKind", schema_for!(music::MusicCmdKind)),
+        ("MusicResponse", schema_for!(music::Response)),
+        ("ErrorResponse", schema_for!(ErrorResponse)),
+    ]
+    .map(|(name, mut schema)| {
+        schema.insert("title".into(), name.into());
+        (name, schema)
+    })
+}
+
+/// Where the schemas are committed.
+pub fn dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("schema")
+}
+
+pub fn file_name(name: &str) -> String {
+    format!("{name}.schema.json")
+}
+
+pub fn render(schema: &Schema) -> String {
+    let mut json = serde_json::to_string_pretty(schema).expect("schemas are valid json");
+    json.push('\n');
+    json
+}
+
+// mirrors how `mlib::queue::Current` is serialized, it doesn't implement `JsonSchema`. Plain
+// comments so this doesn't end up in the schema's description
+#[derive(JsonSchema, Serialize)]
+#[allow(dead_code)]
+pub(crate) struct Current {
+    title: String,
+    artist: Option<String>,
+    chapter: Option<(usize, String)>,
+    playing: bool,
+    volume: f64,
+    progress: Option<f64>,
+    playback_time: Option<Duration>,
+    duration: Duration,
+    categories: Vec<String>,
+    index: usize,
+    next: Option<UpNext>,
+}
+
+// mirrors how `mlib::queue::UpNext` is serialized
+#[derive(JsonSchema, Serialize)]
+#[allow(dead_code)]
+struct UpNext {
+    title: String,
+    artist: Option<String>,
+    categories: Vec<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn committed_schemas_are_up_to_date() {
+        for (name, schema) in all() {
+            let path = dir().join(file_name(name));
+            let committed = std::fs::read_to_string(&path).unwrap_or_default();
+            assert!(
+                committed == render(&schema),
+                "{} is out of date with the wire format, regenerate it with \
+                 `cargo run -p spark-protocol --bin schema`",
+                path.display(),
+            );
+        }
+    }
+
+    #[test]
+    fn mirrors_serialize_like_mlib() {
+        let mirror = Current {
+            title: "title".into(),
+            artist: Some("artist".into()),
+            chapter: Some((2, "chapter".into())),
+            playing: true,
+            volume: 54.,
+            progress: Some(50.),
+            playback_time: Some(Duration::from_secs(2)),
+            duration: Duration::from_secs(60),
+            categories: vec!["category".into()],
+            index: 1,
+            next: Some(UpNext {
+                title: "next".into(),
+                artist: None,
+                categories: vec!["other".into()],
+            }),
+        };
+        let json = serde_json::to_value(&mirror).unwrap();
+        let current = serde_json::from_value::<mlib::queue::Current>(json.clone())
+            .expect("mlib no longer accepts what the schema describes");
+        assert_eq!(
+            serde_json::to_value(&current).unwrap(),
+            json,
+            "mlib::queue::Current changed, update the mirror in schema.rs and regenerate the \
+             schemas"
+        );
+    }
+}
@@ -1,67 +1,133 @@
-use crate::{Command, socket_path};
+use crate::{
+    Capabilities, Command, Reply, RequestId, SuccessfulResponse, music::Subscription, socket_path,
+};
 use common::net::{ReadJsonLinesExt, RecvError, WriteJsonLinesExt};
+use futures::{Stream, StreamExt, stream::BoxStream};
+use serde::{Deserialize, Serialize};
 use std::{
-    fmt::Debug,
+    fmt::{self, Debug},
     fs::Permissions,
     future::Future,
     io,
     os::unix::prelude::PermissionsExt,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 use tokio::{
     fs,
     io::{BufReader, BufWriter},
-    net::{self, UnixListener, UnixStream},
+    net::{self, UnixListener, UnixStream, unix::UCred},
+    sync::mpsc,
 };
 
 use super::ErrorResponse;
 
-struct Client {
-    reader: BufReader<net::unix::OwnedReadHalf>,
-    writer: BufWriter<net::unix::OwnedWriteHalf>,
+/// A line received from a client.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    Request {
+        id: RequestId,
+        command: serde_json::Value,
+    },
+    Bare(serde_json::Value),
 }
 
-impl Client {
-    #[inline(always)]
-    async fn recv(&mut self) -> Result<Option<Command>, RecvError> {
-        self.reader.recv().await
-    }
+/// A line sent to a client, tagged if the request it answers was.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Outgoing {
+    Reply(Reply),
+    Bare(crate::Response),
+}
 
-    #[inline(always)]
-    async fn send(&mut self, r: crate::Response) -> io::Result<()> {
-        self.writer.send(&r).await
-    }
+type Subscriber = Arc<dyn Fn(Subscription) -> BoxStream<'static, crate::Response> + Send + Sync>;
+
+type Authorizer = Arc<dyn Fn(&UCred, &Command) -> Result<(), ErrorResponse> + Send + Sync>;
+
+pub struct ServerBuilder {
+    path: Option<PathBuf>,
+    capabilities: Capabilities,
+    subscriber: Option<Subscriber>,
+    authorizer: Option<Authorizer>,
 }
 
-impl From<UnixStream> for Client {
-    fn from(s: UnixStream) -> Self {
-        let (r, w) = s.into_split();
-        Self {
-            reader: BufReader::new(r),
-            writer: BufWriter::new(w),
-        }
+impl Debug for ServerBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerBuilder")
+            .field("path", &self.path)
+            .field("capabilities", &self.capabilities)
+            .field("subscriber", &self.subscriber.is_some())
+            .field("authorizer", &self.authorizer.is_some())
+            .finish()
     }
 }
 
-#[derive(Default, Debug)]
-pub struct ServerBuilder {
-    path: Option<PathBuf>,
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ServerBuilder {
     pub fn new() -> Self {
-        Self { path: None }
+        Self {
+            path: None,
+            capabilities: Capabilities::all(),
+            subscriber: None,
+            authorizer: None,
+        }
     }
 
     pub fn with_path(self, path: PathBuf) -> ServerBuilder {
-        ServerBuilder { path: Some(path) }
+        ServerBuilder {
+            path: Some(path),
+            ..self
+        }
+    }
+
+    /// Restricts the commands that will be forwarded to the handler. Anything else is answered
+    /// with [`ErrorResponse::Unsupported`].
+    pub fn with_capabilities(self, capabilities: Capabilities) -> ServerBuilder {
+        ServerBuilder {
+            capabilities,
+            ..self
+        }
+    }
+
+    /// Enables [`Command::Subscribe`]. Once a client subscribes, the connection is dedicated to
+    /// forwarding the events produced by `subscriber` until the client disconnects.
+    pub fn with_subscriptions<F, S>(self, subscriber: F) -> ServerBuilder
+    where
+        F: Fn(Subscription) -> S + Send + Sync + 'static,
+        S: Stream<Item = crate::Response> + Send + 'static,
+    {
+        ServerBuilder {
+            subscriber: Some(Arc::new(move |s| subscriber(s).boxed())),
+            ..self
+        }
+    }
+
+    /// Checks every command, subscriptions included, against the credentials of the process that
+    /// sent it. Refused commands are answered with the error `authorizer` returns instead of
+    /// reaching the handler. Handshakes are always answered.
+    ///
+    /// Connections whose peer credentials can't be read are dropped.
+    pub fn with_authorizer<F>(self, authorizer: F) -> ServerBuilder
+    where
+        F: Fn(&UCred, &Command) -> Result<(), ErrorResponse> + Send + Sync + 'static,
+    {
+        ServerBuilder {
+            authorizer: Some(Arc::new(authorizer)),
+            ..self
+        }
     }
 
     // TODO: move to spark and finish implementing
     pub async fn serve<F, Fut>(self, handler: F) -> io::Result<impl Future<Output = ()>>
     where
         F: Fn(Command) -> Fut + Clone + Send + 'static,
-        Fut: Future<Output = crate::Response> + Send,
+        Fut: Future<Output = crate::Response> + Send + 'static,
     {
         async fn create_socket<P: AsRef<Path> + Debug>(p: P) -> io::Result<UnixListener> {
             if let Err(e) = fs::remove_file(&p).await
@@ -72,6 +138,7 @@ impl ServerBuilder {
             }
             tracing::info!(path = ?p, "binding ipc socket");
             let socket = UnixListener::bind(&p)?;
+            // who may talk to the server is up to the authorizer
             fs::set_permissions(p, Permissions::from_mode(0o777)).await?;
             Ok(socket)
         }
@@ -79,6 +146,13 @@ impl ServerBuilder {
             Some(p) => create_socket(p).await?,
             None => create_socket(socket_path().await?).await?,
         };
+        let (capabilities, subscriber) = match self.subscriber {
+            Some(s) if self.capabilities.commands.contains("Subscribe") => {
+                (self.capabilities, Some(s))
+            }
+            _ => (self.capabilities.without("Subscribe"), None),
+        };
+        let authorizer = self.authorizer;
         Ok(async move {
             let mut id = 0;
             loop {
@@ -91,37 +165,165 @@ impl ServerBuilder {
                 };
                 let local_id = id;
                 id += 1;
-                tokio::spawn({
-                    let handler = handler.clone();
-                    async move {
-                        let mut client = Client::from(client);
-                        loop {
-                            let rcv = client.recv().await;
-                            tracing::info!(%local_id, req = ?rcv, "received local request");
-                            match rcv {
-                                Ok(Some(c)) => {
-                                    let response = handler(c);
-                                    client.send(response.await).await?;
-                                }
-                                Ok(None) => break,
-                                Err(RecvError::Io(e)) => return Err(e),
-                                Err(RecvError::Serde(s)) => {
-                                    client
-                                        .send(Err(ErrorResponse::DeserializingCommand(
-                                            s.to_string(),
-                                        )))
-                                        .await?;
-                                }
-                            }
-                        }
-                        io::Result::Ok(())
+                let authorizer = match (&authorizer, client.peer_cred()) {
+                    (None, _) => None,
+                    (Some(authorizer), Ok(peer)) => {
+                        tracing::info!(%local_id, uid = peer.uid(), pid = ?peer.pid(), "accepted");
+                        Some((authorizer.clone(), peer))
+                    }
+                    (Some(_), Err(e)) => {
+                        tracing::error!(%local_id, ?e, "failed to get peer credentials");
+                        continue;
                     }
-                });
+                };
+                tokio::spawn(serve_connection(
+                    client,
+                    local_id,
+                    handler.clone(),
+                    capabilities.clone(),
+                    subscriber.clone(),
+                    authorizer,
+                ));
             }
         })
     }
 }
 
+async fn serve_connection<F, Fut>(
+    client: UnixStream,
+    local_id: usize,
+    handler: F,
+    capabilities: Capabilities,
+    subscriber: Option<Subscriber>,
+    authorizer: Option<(Authorizer, UCred)>,
+) -> io::Result<()>
+where
+    F: Fn(Command) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = crate::Response> + Send + 'static,
+{
+    let (reader, writer) = client.into_split();
+    let (mut reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
+    let (tx, mut rx) = mpsc::channel::<Outgoing>(32);
+
+    let answer = move |command: Command| {
+        let handler = handler.clone();
+        let capabilities = capabilities.clone();
+        async move {
+            match command {
+                Command::Handshake(remote) => {
+                    tracing::info!(%local_id, ?remote, "handshake");
+                    Ok(SuccessfulResponse::Handshake(capabilities))
+                }
+                command => match capabilities.check(&command) {
+                    Ok(()) => handler(command).await,
+                    Err(e) => Err(e),
+                },
+            }
+        }
+    };
+
+    let reading = async move {
+        loop {
+            let rcv = reader.recv::<Incoming>().await;
+            tracing::info!(%local_id, req = ?rcv, "received local request");
+            let (id, command) = match rcv {
+                Ok(Some(Incoming::Request { id, command })) => (Some(id), command),
+                Ok(Some(Incoming::Bare(command))) => (None, command),
+                Ok(None) => break,
+                Err(RecvError::Io(e)) => return Err(e),
+                Err(RecvError::Serde(s)) => {
+                    let _ = tx
+                        .send(Outgoing::Bare(Err(ErrorResponse::DeserializingCommand(
+                            s.to_string(),
+                        ))))
+                        .await;
+                    continue;
+                }
+            };
+            let tag = move |response| match id {
+                Some(id) => Outgoing::Reply(Reply { id, response }),
+                None => Outgoing::Bare(response),
+            };
+            let command = match serde_json::from_value::<Command>(command) {
+                Ok(command) => command,
+                Err(e) => {
+                    let _ = tx
+                        .send(tag(Err(ErrorResponse::DeserializingCommand(e.to_string()))))
+                        .await;
+                    continue;
+                }
+            };
+            if let Some((authorize, peer)) = &authorizer
+                && !matches!(command, Command::Handshake(_))
+                && let Err(e) = authorize(peer, &command)
+            {
+                tracing::warn!(%local_id, uid = peer.uid(), command = command.name(), "refused");
+                let _ = tx.send(tag(Err(e))).await;
+                continue;
+            }
+            match (command, &subscriber) {
+                (Command::Subscribe(subscription), Some(subscriber)) => {
+                    tracing::info!(%local_id, ?id, ?subscription, "subscribing");
+                    return stream(subscriber(subscription), tag, &tx, &mut reader).await;
+                }
+                // untagged commands have to be answered in the order they were sent
+                (command, _) if id.is_none() => {
+                    let _ = tx.send(tag(answer(command).await)).await;
+                }
+                (command, _) => {
+                    let response = answer(command);
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        let _ = tx.send(tag(response.await)).await;
+                    });
+                }
+            }
+        }
+        io::Result::Ok(())
+    };
+
+    let writing = async move {
+        while let Some(outgoing) = rx.recv().await {
+            writer.send(&outgoing).await?;
+        }
+        io::Result::Ok(())
+    };
+
+    tokio::try_join!(reading, writing).map(|_| ())
+}
+
+/// Forwards every event to the client until either the events run out or the client hangs up.
+/// The connection is dedicated to the subscription from then on.
+async fn stream(
+    mut events: BoxStream<'static, crate::Response>,
+    tag: impl Fn(crate::Response) -> Outgoing,
+    tx: &mpsc::Sender<Outgoing>,
+    reader: &mut BufReader<net::unix::OwnedReadHalf>,
+) -> io::Result<()> {
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => {
+                    if tx.send(tag(event)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            },
+            rcv = reader.recv::<Incoming>() => match rcv {
+                Ok(None) => return Ok(()),
+                Ok(Some(cmd)) => {
+                    tracing::warn!(?cmd, "ignoring command sent while subscribed");
+                }
+                Err(RecvError::Io(e)) => return Err(e),
+                Err(RecvError::Serde(e)) => {
+                    tracing::warn!(?e, "ignoring malformed command sent while subscribed");
+                }
+            },
+        }
+    }
+}
+
 pub async fn server<F, Fut>(handler: F) -> io::Result<impl Future<Output = ()>>
 where
     F: Fn(Command) -> Fut + Clone + Send + 'static,
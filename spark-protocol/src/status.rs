@@ -0,0 +1,135 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// How the background tasks of a running daemon are doing.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct DaemonStatus {
+    pub version: String,
+    pub uptime: Duration,
+    pub ipc_socket_path: PathBuf,
+    pub persistent_conn: PersistentConnStatus,
+    pub machine_status: MachineStatusStatus,
+}
+
+/// The socket.io connection to the backend.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct PersistentConnStatus {
+    pub connected: bool,
+    /// How long ago the connection last came up or went down, `None` if it never came up.
+    pub since: Option<Duration>,
+    /// How many times the connection came up again after the first time.
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+}
+
+/// The periodic posts of the machine status to the backend.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct MachineStatusStatus {
+    /// How long ago the status was last posted successfully.
+    pub last_success: Option<Duration>,
+    /// The status code the backend answered the last post with.
+    pub last_status_code: Option<u16>,
+    /// Failed posts since the last successful one.
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// A duration as the two largest units that are not zero, like `3d 2h` or `5m 7s`.
+struct Elapsed(Duration);
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let units = [
+            (secs / 86400, "d"),
+            (secs / 3600 % 24, "h"),
+            (secs / 60 % 60, "m"),
+            (secs % 60, "s"),
+        ];
+        let first = units.iter().position(|(n, _)| *n > 0).unwrap_or(3);
+        let mut parts = units[first..].iter().take(2).filter(|(n, _)| *n > 0);
+        let Some((n, unit)) = parts.next() else {
+            return f.write_str("0s");
+        };
+        write!(f, "{n}{unit}")?;
+        for (n, unit) in parts {
+            write!(f, " {n}{unit}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DaemonStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "uptime: {}", Elapsed(self.uptime))?;
+        writeln!(f, "ipc socket: {}", self.ipc_socket_path.display())?;
+
+        let conn = &self.persistent_conn;
+        f.write_str("persistent connection: ")?;
+        match (conn.connected, conn.since) {
+            (true, Some(since)) => write!(f, "up for {}", Elapsed(since))?,
+            (false, Some(since)) => write!(f, "down for {}", Elapsed(since))?,
+            (_, None) => f.write_str("never connected")?,
+        }
+        writeln!(f, ", {} reconnects", conn.reconnects)?;
+        if let Some(e) = &conn.last_error {
+            writeln!(f, "   last error: {e}")?;
+        }
+
+        let status = &self.machine_status;
+        f.write_str("machine status: ")?;
+        match status.last_success {
+            Some(ago) => write!(f, "last posted {} ago", Elapsed(ago))?,
+            None => f.write_str("never posted")?,
+        }
+        if let Some(code) = status.last_status_code {
+            write!(f, ", last answered with {code}")?;
+        }
+        if status.failures > 0 {
+            write!(f, ", {} failures since", status.failures)?;
+        }
+        if let Some(e) = &status.last_error {
+            write!(f, "\n   last error: {e}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let status = DaemonStatus {
+            version: "0.5.20".into(),
+            uptime: Duration::from_secs(3 * 86400 + 2 * 3600 + 5 * 60),
+            ipc_socket_path: "/tmp/spark/socket".into(),
+            persistent_conn: PersistentConnStatus {
+                connected: true,
+                since: Some(Duration::from_secs(125)),
+                reconnects: 2,
+                last_error: Some("connection reset".into()),
+            },
+            machine_status: MachineStatusStatus {
+                last_success: Some(Duration::from_secs(7)),
+                last_status_code: Some(500),
+                failures: 1,
+                last_error: Some("Post request failed: 500".into()),
+            },
+        };
+        assert_eq!(
+            status.to_string(),
+            "version: 0.5.20
+uptime: 3d 2h
+ipc socket: /tmp/spark/socket
+persistent connection: up for 2m 5s, 2 reconnects
+   last error: connection reset
+machine status: last posted 7s ago, last answered with 500, 1 failures since
+   last error: Post request failed: 500"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A snapshot of the health of a machine.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct SystemInfo {
+    pub uptime: Duration,
+    pub load_average: LoadAverage,
+    pub memory: Memory,
+    pub disks: Vec<Disk>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub batteries: Vec<Battery>,
+    /// In degrees Celsius.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_temperature: Option<f64>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+/// Sizes are in bytes.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct Memory {
+    pub total: u64,
+    pub available: u64,
+}
+
+/// Sizes are in bytes.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct Disk {
+    pub device: String,
+    pub mount_point: PathBuf,
+    pub total: u64,
+    pub available: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct Battery {
+    pub name: String,
+    /// Charge, as a percentage.
+    pub capacity: u8,
+    /// Charging, Discharging, Full, etc.
+    pub status: String,
+}
+
+struct Bytes(u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut size = self.0 as f64;
+        let mut unit = 0;
+        while size >= 1024. && unit < UNITS.len() - 1 {
+            size /= 1024.;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} {}", self.0, UNITS[unit])
+        } else {
+            write!(f, "{size:.1} {}", UNITS[unit])
+        }
+    }
+}
+
+/// Space used out of the total.
+struct Usage {
+    total: u64,
+    available: u64,
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let used = self.total.saturating_sub(self.available);
+        let percent = if self.total == 0 {
+            0.
+        } else {
+            used as f64 / self.total as f64 * 100.
+        };
+        write!(f, "{} / {} ({percent:.0}%)", Bytes(used), Bytes(self.total))
+    }
+}
+
+impl fmt::Display for SystemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.uptime.as_secs();
+        let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+        f.write_str("uptime: ")?;
+        if days > 0 {
+            write!(f, "{days}d ")?;
+        }
+        writeln!(f, "{hours}h {minutes}m")?;
+
+        let LoadAverage { one, five, fifteen } = self.load_average;
+        writeln!(f, "load average: {one:.2} {five:.2} {fifteen:.2}")?;
+
+        let Memory { total, available } = self.memory;
+        write!(f, "memory: {}", Usage { total, available })?;
+
+        if !self.disks.is_empty() {
+            write!(f, "\ndisks:")?;
+            let width = self
+                .disks
+                .iter()
+                .map(|d| d.mount_point.as_os_str().len())
+                .max()
+                .unwrap_or_default();
+            for disk in &self.disks {
+                write!(
+                    f,
+                    "\n   {:width$}  {}  {}",
+                    disk.mount_point.display(),
+                    Usage {
+                        total: disk.total,
+                        available: disk.available
+                    },
+                    disk.device,
+                )?;
+            }
+        }
+        for battery in &self.batteries {
+            write!(
+                f,
+                "\nbattery {}: {}% ({})",
+                battery.name, battery.capacity, battery.status
+            )?;
+        }
+        if let Some(temperature) = self.cpu_temperature {
+            write!(f, "\ncpu temperature: {temperature:.1} °C")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let info = SystemInfo {
+            uptime: Duration::from_secs(3 * 86400 + 2 * 3600 + 5 * 60 + 7),
+            load_average: LoadAverage {
+                one: 0.5,
+                five: 0.25,
+                fifteen: 1.,
+            },
+            memory: Memory {
+                total: 4 * 1024 * 1024 * 1024,
+                available: 3 * 1024 * 1024 * 1024,
+            },
+            disks: vec![
+                Disk {
+                    device: "/dev/mmcblk0p2".into(),
+                    mount_point: "/".into(),
+                    total: 1024,
+                    available: 256,
+                },
+                Disk {
+                    device: "/dev/mmcblk0p1".into(),
+                    mount_point: "/boot".into(),
+                    total: 512 * 1024 * 1024,
+                    available: 0,
+                },
+            ],
+            batteries: vec![Battery {
+                name: "BAT0".into(),
+                capacity: 87,
+                status: "Discharging".into(),
+            }],
+            cpu_temperature: Some(48.312),
+        };
+        assert_eq!(
+            info.to_string(),
+            "uptime: 3d 2h 5m
+load average: 0.50 0.25 1.00
+memory: 1.0 GiB / 4.0 GiB (25%)
+disks:
+   /      768 B / 1.0 KiB (75%)  /dev/mmcblk0p2
+   /boot  512.0 MiB / 512.0 MiB (100%)  /dev/mmcblk0p1
+battery BAT0: 87% (Discharging)
+cpu temperature: 48.3 °C"
+        );
+    }
+}
@@ -0,0 +1,7 @@
+fn main() {
+    // used to ask the backend for builds of the same target when self updating
+    println!(
+        "cargo:rustc-env=TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
+}
@@ -1,13 +1,14 @@
 mod songs;
 
 use crate::{config::Config, util::get_hostname};
+use anyhow::Context;
 use chrono::Utc;
 use common::{
-    domain::{Hostname, music_session::ExpiresAt},
+    domain::{Hostname, music_session::ExpiresAt, spark_build::SparkBuild},
     net::AuthenticatedClient,
 };
 use mlib::item::link::BangerId;
-use std::time::Duration;
+use std::{path::Path, time::Duration};
 
 pub(super) async fn handle(cmd: super::Backend, config: Config) -> anyhow::Result<()> {
     let client = AuthenticatedClient::try_from(&config)?;
@@ -46,6 +47,11 @@ pub(super) async fn handle(cmd: super::Backend, config: Config) -> anyhow::Resul
         crate::Backend::SyncPlaylists => {
             songs::sync_playlists(client).await?;
         }
+        crate::Backend::UploadBuild {
+            target,
+            version,
+            path,
+        } => upload_build(client, target, version, &path).await?,
     }
     Ok(())
 }
@@ -102,3 +108,28 @@ async fn delete_music_session(client: AuthenticatedClient, session: String) -> a
     println!("session deleted");
     Ok(())
 }
+
+async fn upload_build(
+    client: AuthenticatedClient,
+    target: String,
+    version: String,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let binary = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("reading {}", path.display()))?;
+    let build = client
+        .post(&format!("/spark/builds/{target}/{version}"))?
+        .body(binary)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<SparkBuild>()
+        .await?;
+
+    println!(
+        "uploaded {} {} ({} bytes, md5 {})",
+        build.target, build.version, build.size, build.checksum
+    );
+    Ok(())
+}
@@ -1,18 +1,25 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use anyhow::Context;
 use common::{
+    algorithms::net_graph::Staleness,
     domain::Hostname,
     net::{
         AuthenticatedClient, auth_client::UrlParseError, defaults::default_persistent_conn_port,
     },
 };
 use dirs::config_dir;
+use tokio::sync::watch;
 use url::Url;
 
 use crate::util::destination::Destination;
 
-#[derive(Debug, serde::Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
 pub struct Config {
     pub token: uuid::Uuid,
     pub backend_domain: Url,
@@ -26,9 +33,27 @@ pub struct Config {
     pub default_user: Option<String>,
     #[serde(default = "crate::config::ipc_socket_path")]
     pub ipc_socket_path: PathBuf,
+    #[serde(default)]
+    pub exec: Exec,
+    /// Commands that can be run by name through [`spark_protocol::Command::Custom`]. Like the
+    /// programs in [`Exec`], they can only be changed by reloading.
+    #[serde(default)]
+    pub custom: HashMap<String, CustomCommand>,
+    /// Labels this machine can be targeted by when broadcasting commands.
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub update: Update,
+    #[serde(default)]
+    pub ipc: Ipc,
+    #[serde(default)]
+    pub heartbeat: Heartbeat,
+    /// Serves Prometheus metrics about the daemon, if set.
+    #[serde(default)]
+    pub metrics: Option<Metrics>,
 }
 
-#[derive(Debug, serde::Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
 pub struct PersistentConn {
     #[serde(default = "::common::net::defaults::default_persistent_conn_port")]
     pub port: u16,
@@ -42,12 +67,223 @@ impl Default for PersistentConn {
     }
 }
 
-#[derive(Debug, Clone, serde::Deserialize, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Default, PartialEq, Eq)]
 pub struct Networking {
     #[serde(default)]
     pub ssh: Option<u16>,
     #[serde(default)]
     pub aliases: HashMap<String, Destination>,
+    /// How routes treat machines that stopped posting their status.
+    #[serde(default)]
+    pub staleness: Staleness,
+}
+
+/// Programs that admins may run remotely through [`spark_protocol::Command::Exec`].
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Default, PartialEq, Eq)]
+pub struct Exec {
+    #[serde(default)]
+    pub allowed: HashSet<String>,
+}
+
+/// A program declared in the config, run with its arguments filled in from those of the
+/// [`spark_protocol::Command::Custom`] that named it.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
+pub struct CustomCommand {
+    pub program: String,
+    /// `{1}`, `{2}`, ... are replaced by the arguments the command was sent with, and an
+    /// argument that is just `{*}` by all of those that weren't used by position.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The program is killed if it's still running after this many seconds.
+    #[serde(default = "crate::config::default_custom_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_custom_timeout_secs() -> u64 {
+    30
+}
+
+/// How self updates through [`spark_protocol::Command::Update`] are handled.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
+pub struct Update {
+    /// An updated spark that hasn't connected to the backend after this many seconds is
+    /// replaced by the previous version.
+    #[serde(default = "crate::config::default_rollback_after_secs")]
+    pub rollback_after_secs: u64,
+}
+
+impl Default for Update {
+    fn default() -> Self {
+        Self {
+            rollback_after_secs: default_rollback_after_secs(),
+        }
+    }
+}
+
+fn default_rollback_after_secs() -> u64 {
+    120
+}
+
+/// Who, besides the user running the daemon, may use its ipc socket.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Default, PartialEq, Eq)]
+pub struct Ipc {
+    #[serde(default)]
+    pub allowed_uids: HashSet<u32>,
+    /// Names of groups whose members are allowed.
+    #[serde(default)]
+    pub allowed_groups: HashSet<String>,
+}
+
+/// How the machine status is posted to the backend.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// Seconds between posts.
+    #[serde(default = "crate::config::default_heartbeat_interval_secs")]
+    pub interval_secs: u64,
+    /// Each interval is made up to this many seconds shorter or longer, so machines that
+    /// started together don't keep posting together.
+    #[serde(default = "crate::config::default_heartbeat_jitter_secs")]
+    pub jitter_secs: u64,
+    /// How long the backend has to answer a post.
+    #[serde(default = "crate::config::default_heartbeat_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Longest wait between posts while the backend is unreachable.
+    #[serde(default = "crate::config::default_heartbeat_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    /// Seconds between measuring how well the machines on the same network can be reached,
+    /// which is posted along with the status. `0` turns it off.
+    #[serde(default = "crate::config::default_heartbeat_probe_interval_secs")]
+    pub probe_interval_secs: u64,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_heartbeat_interval_secs(),
+            jitter_secs: default_heartbeat_jitter_secs(),
+            timeout_secs: default_heartbeat_timeout_secs(),
+            max_backoff_secs: default_heartbeat_max_backoff_secs(),
+            probe_interval_secs: default_heartbeat_probe_interval_secs(),
+        }
+    }
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    60
+}
+
+fn default_heartbeat_jitter_secs() -> u64 {
+    5
+}
+
+fn default_heartbeat_timeout_secs() -> u64 {
+    10
+}
+
+fn default_heartbeat_max_backoff_secs() -> u64 {
+    600
+}
+
+fn default_heartbeat_probe_interval_secs() -> u64 {
+    300
+}
+
+/// Where the metrics are served, at `/metrics`.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
+pub struct Metrics {
+    #[serde(default = "crate::config::default_metrics_address")]
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+fn default_metrics_address() -> IpAddr {
+    Ipv4Addr::UNSPECIFIED.into()
+}
+
+/// The config of a running daemon, which [`Config::apply_live`] changes can be sent through.
+pub type Shared = watch::Sender<Arc<Config>>;
+
+impl Config {
+    /// The config as json, without the token.
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut json = serde_json::to_value(self).expect("configs always serialize");
+        if let Some(fields) = json.as_object_mut() {
+            fields.remove("token");
+        }
+        json
+    }
+
+    /// Applies a json merge patch to this config.
+    pub fn patched(&self, patch: &serde_json::Value) -> anyhow::Result<Config> {
+        let mut json = serde_json::to_value(self).expect("configs always serialize");
+        merge(&mut json, patch);
+        serde_json::from_value(json).context("the patched config is invalid")
+    }
+
+    /// The settings of `new` that can change while the daemon runs, applied to this config.
+    /// Fails, naming the setting, if anything else differs.
+    pub fn apply_live(&self, new: &Config) -> anyhow::Result<Config> {
+        let mut live = self.clone();
+        live.network.ssh = new.network.ssh;
+        live.network.aliases = new.network.aliases.clone();
+        live.network.staleness = new.network.staleness;
+        live.default_user = new.default_user.clone();
+
+        let (live_json, new_json) = (
+            serde_json::to_value(&live).expect("configs always serialize"),
+            serde_json::to_value(new).expect("configs always serialize"),
+        );
+        if let (Some(live_json), Some(new_json)) = (live_json.as_object(), new_json.as_object())
+            && let Some(key) = live_json
+                .keys()
+                .find(|key| live_json.get(*key) != new_json.get(*key))
+        {
+            anyhow::bail!("{key} can only be changed by reloading");
+        }
+        Ok(live)
+    }
+}
+
+/// Replaces the config of the running daemon with what `change` makes of it, unless it fails.
+pub fn update<F>(shared: &Shared, change: F) -> anyhow::Result<Arc<Config>>
+where
+    F: FnOnce(&Config) -> anyhow::Result<Config>,
+{
+    let mut result = Err(anyhow::anyhow!("config was not changed"));
+    shared.send_if_modified(|current| match change(current) {
+        Ok(new) => {
+            let modified = new != **current;
+            *current = Arc::new(new);
+            result = Ok(current.clone());
+            modified
+        }
+        Err(e) => {
+            result = Err(e);
+            false
+        }
+    });
+    result
+}
+
+/// Json merge patch, as described in RFC 7396.
+fn merge(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let Some(patch) = patch.as_object() else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(Default::default());
+    }
+    let target = target
+        .as_object_mut()
+        .expect("just made sure it's an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge(target.entry(key).or_insert(serde_json::Value::Null), value);
+        }
+    }
 }
 
 impl TryFrom<&Config> for AuthenticatedClient {
@@ -107,4 +343,70 @@ mod test {
         assert_eq!(conf.network.ssh, None);
         assert_eq!(conf.network.aliases, HashMap::default());
     }
+
+    #[test]
+    fn nothing_can_be_executed_by_default() {
+        let conf = r#"{
+            "token": "e751e207-59a8-4797-ab04-e8884b67e68e",
+            "backend_domain": "http://url"
+        }"#;
+        let conf = serde_json::from_str::<Config>(conf).unwrap();
+        assert!(conf.exec.allowed.is_empty());
+    }
+
+    fn config() -> Config {
+        serde_json::from_str(
+            r#"{
+                "token": "e751e207-59a8-4797-ab04-e8884b67e68e",
+                "backend_domain": "http://url",
+                "network": { "ssh": 22 },
+                "default_user": "mendess"
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn the_token_is_redacted() {
+        let redacted = config().redacted();
+        assert!(redacted.get("token").is_none());
+        assert_eq!(redacted["default_user"], "mendess");
+    }
+
+    #[test]
+    fn live_settings_can_be_patched() {
+        let current = config();
+        let patched = current
+            .patched(&serde_json::json!({
+                "network": { "ssh": 2222 },
+                "default_user": null,
+            }))
+            .unwrap();
+        let live = current.apply_live(&patched).unwrap();
+        assert_eq!(live.network.ssh, Some(2222));
+        assert_eq!(live.default_user, None);
+        assert_eq!(live.token, current.token);
+    }
+
+    #[test]
+    fn other_settings_need_a_reload() {
+        let current = config();
+        let patched = current
+            .patched(&serde_json::json!({ "tags": ["speakers"] }))
+            .unwrap();
+        let e = current.apply_live(&patched).unwrap_err();
+        assert_eq!(e.to_string(), "tags can only be changed by reloading");
+    }
+
+    #[test]
+    fn custom_commands_cant_be_patched_in() {
+        let current = config();
+        let patched = current
+            .patched(&serde_json::json!({
+                "custom": { "x": { "program": "sh", "args": ["-c", "{1}"] } },
+            }))
+            .unwrap();
+        let e = current.apply_live(&patched).unwrap_err();
+        assert_eq!(e.to_string(), "custom can only be changed by reloading");
+    }
 }
@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use spark_protocol::ErrorResponse;
+
+use crate::config::Config;
+
+pub async fn handle(config: &Config, name: String, args: Vec<String>) -> spark_protocol::Response {
+    let Some(custom) = config.custom.get(&name) else {
+        return Err(ErrorResponse::RequestFailed(format!(
+            "{name} is not a custom command of this spark"
+        )));
+    };
+    let args = expand(&custom.args, &args)
+        .map_err(|e| ErrorResponse::RequestFailed(format!("{name}: {e}")))?;
+    super::exec::run(
+        &custom.program,
+        &args,
+        Duration::from_secs(custom.timeout_secs),
+    )
+    .await
+}
+
+/// Fills in the argument template of a [`crate::config::CustomCommand`] with the arguments it was sent with.
+/// Every argument has to end up somewhere.
+fn expand(template: &[String], given: &[String]) -> Result<Vec<String>, String> {
+    let mut used = vec![false; given.len()];
+    let mut rest_at = None;
+    let mut expanded = Vec::with_capacity(template.len());
+    for arg in template {
+        if arg == "{*}" {
+            rest_at = Some(expanded.len());
+            continue;
+        }
+        let mut out = String::with_capacity(arg.len());
+        let mut chars = arg.char_indices();
+        while let Some((i, c)) = chars.next() {
+            let placeholder = (c == '{')
+                .then(|| arg[i + 1..].split_once('}'))
+                .flatten()
+                .and_then(|(n, _)| n.parse::<usize>().ok().map(|index| (n.len(), index)));
+            let Some((len, index)) = placeholder else {
+                out.push(c);
+                continue;
+            };
+            let Some(value) = index.checked_sub(1).and_then(|i| given.get(i)) else {
+                return Err(format!(
+                    "expects at least {index} arguments, got {}",
+                    given.len()
+                ));
+            };
+            used[index - 1] = true;
+            out.push_str(value);
+            chars.nth(len);
+        }
+        expanded.push(out);
+    }
+    let rest = given.iter().zip(&used).filter(|(_, used)| !**used);
+    match rest_at {
+        Some(at) => {
+            expanded.splice(at..at, rest.map(|(arg, _)| arg.clone()));
+        }
+        None => {
+            if let Some((unused, _)) = rest.into_iter().next() {
+                return Err(format!("does not take the argument {unused:?}"));
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn placeholders_are_filled_in_by_position() {
+        let template = strings(&["up", "--iface={2}", "{1}"]);
+        assert_eq!(
+            expand(&template, &strings(&["work", "wg0"])).unwrap(),
+            strings(&["up", "--iface=wg0", "work"])
+        );
+    }
+
+    #[test]
+    fn the_rest_goes_where_the_star_is() {
+        let template = strings(&["-t", "{1}", "{*}", "--end"]);
+        assert_eq!(
+            expand(&template, &strings(&["5", "a", "b"])).unwrap(),
+            strings(&["-t", "5", "a", "b", "--end"])
+        );
+    }
+
+    #[test]
+    fn missing_and_unused_arguments_are_refused() {
+        let template = strings(&["{2}"]);
+        assert!(expand(&template, &strings(&["a"])).is_err());
+        assert!(expand(&template, &strings(&["a", "b", "c"])).is_err());
+        assert!(expand(&[], &strings(&["a"])).is_err());
+    }
+
+    #[test]
+    fn braces_that_are_not_placeholders_are_kept() {
+        let template = strings(&["{}", "{x}", "{1"]);
+        assert_eq!(
+            expand(&template, &[]).unwrap(),
+            strings(&["{}", "{x}", "{1"])
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::{process::Stdio, time::Duration};
+
+use spark_protocol::{
+    ErrorResponse, SuccessfulResponse,
+    exec::{Exec, ExecOutput},
+};
+use tokio::{process::Command, time::timeout};
+
+use crate::config::Config;
+
+/// Programs still running after this long are killed, so that a stuck program can't hold up
+/// the relay waiting for its reply.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub async fn handle(config: &Config, Exec { program, args }: Exec) -> spark_protocol::Response {
+    if !config.exec.allowed.contains(&program) {
+        return Err(ErrorResponse::RequestFailed(format!(
+            "{program} is not in the list of programs allowed to be executed"
+        )));
+    }
+    run(&program, &args, TIMEOUT).await
+}
+
+/// Runs `program` to completion, or until `limit` runs out, and collects its output.
+pub(super) async fn run(
+    program: &str,
+    args: &[String],
+    limit: Duration,
+) -> spark_protocol::Response {
+    tracing::info!(%program, ?args, "executing");
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = match timeout(limit, output).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => return Err(ErrorResponse::IoError(e.to_string())),
+        Err(_) => {
+            return Err(ErrorResponse::RequestFailed(format!(
+                "{program} took longer than {}s, killed it",
+                limit.as_secs()
+            )));
+        }
+    };
+    Ok(SuccessfulResponse::ExecOutput(ExecOutput {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        status: output.status.code(),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(allowed: &str) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "token": "e751e207-59a8-4797-ab04-e8884b67e68e",
+            "backend_domain": "http://url",
+            "exec": { "allowed": [allowed] },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn programs_outside_the_allow_list_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let exec = Exec {
+            program: "touch".into(),
+            args: vec![marker.display().to_string()],
+        };
+        assert!(handle(&config("true"), exec.clone()).await.is_err());
+        assert!(!marker.exists());
+
+        assert!(handle(&config("touch"), exec).await.is_ok());
+        assert!(marker.exists());
+    }
+
+    #[tokio::test]
+    async fn programs_that_time_out_are_killed() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("finished");
+        let script = format!("sleep 1 && touch {}", marker.display());
+        let response = run("sh", &["-c".into(), script], Duration::from_millis(100)).await;
+        assert!(response.is_err());
+
+        // had it survived, the shell would have touched the marker by now
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!marker.exists());
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    ffi::OsString,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use spark_protocol::{
+    ErrorResponse, SuccessfulResponse,
+    files::{self, CHUNK_SIZE, FileChunk, GetFile, MAX_FILE_SIZE, PutFile},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+fn resolve(path: PathBuf) -> Result<PathBuf, ErrorResponse> {
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        dirs::home_dir()
+            .map(|home| home.join(path))
+            .ok_or_else(|| ErrorResponse::RequestFailed("no home directory to put files in".into()))
+    }
+}
+
+/// Where a file is assembled while its chunks arrive.
+fn partial(path: &Path) -> Result<PathBuf, ErrorResponse> {
+    let Some(name) = path.file_name() else {
+        return Err(ErrorResponse::RequestFailed(format!(
+            "{} is not a file path",
+            path.display()
+        )));
+    };
+    let mut partial = OsString::from(".");
+    partial.push(name);
+    partial.push(".spark-part");
+    Ok(path.with_file_name(partial))
+}
+
+fn io_error(e: io::Error) -> ErrorResponse {
+    ErrorResponse::IoError(e.to_string())
+}
+
+pub async fn put(
+    PutFile {
+        path,
+        offset,
+        total_size,
+        checksum,
+        data,
+    }: PutFile,
+) -> spark_protocol::Response {
+    if total_size > MAX_FILE_SIZE {
+        return Err(ErrorResponse::RequestFailed(format!(
+            "file is too big, at most {MAX_FILE_SIZE} bytes can be transferred"
+        )));
+    }
+    let Some(end) = offset
+        .checked_add(data.len() as u64)
+        .filter(|&end| data.len() <= CHUNK_SIZE && end <= total_size)
+    else {
+        return Err(ErrorResponse::RequestFailed(format!(
+            "chunk of {} bytes at {offset} does not fit in a {total_size} byte file",
+            data.len()
+        )));
+    };
+    let path = resolve(path)?;
+    let partial = partial(&path)?;
+
+    let mut file = if offset == 0 {
+        tracing::info!(?path, total_size, "receiving file");
+        File::create(&partial).await
+    } else {
+        OpenOptions::new().append(true).open(&partial).await
+    }
+    .map_err(io_error)?;
+    let received = file.metadata().await.map_err(io_error)?.len();
+    if received != offset {
+        return Err(ErrorResponse::RequestFailed(format!(
+            "expected the chunk at offset {received}, got {offset}"
+        )));
+    }
+    file.write_all(&data).await.map_err(io_error)?;
+    file.flush().await.map_err(io_error)?;
+    drop(file);
+
+    if end == total_size {
+        let contents = fs::read(&partial).await.map_err(io_error)?;
+        if files::checksum(&contents) != checksum {
+            if let Err(e) = fs::remove_file(&partial).await {
+                tracing::warn!(error = ?e, ?partial, "failed to remove partial file");
+            }
+            return Err(ErrorResponse::RequestFailed(
+                "checksum mismatch, file was discarded".into(),
+            ));
+        }
+        fs::rename(&partial, &path).await.map_err(io_error)?;
+        tracing::info!(?path, "received file");
+    }
+    Ok(SuccessfulResponse::Unit)
+}
+
+pub async fn get(GetFile { path, offset }: GetFile) -> spark_protocol::Response {
+    let path = resolve(path)?;
+    let mut file = File::open(&path).await.map_err(io_error)?;
+    let total_size = file.metadata().await.map_err(io_error)?.len();
+    if total_size > MAX_FILE_SIZE {
+        return Err(ErrorResponse::RequestFailed(format!(
+            "file is too big, at most {MAX_FILE_SIZE} bytes can be transferred"
+        )));
+    }
+    if offset > total_size {
+        return Err(ErrorResponse::RequestFailed(format!(
+            "offset {offset} is past the end of the {total_size} byte file"
+        )));
+    }
+    file.seek(SeekFrom::Start(offset)).await.map_err(io_error)?;
+    let mut data = Vec::with_capacity(CHUNK_SIZE.min((total_size - offset) as usize));
+    (&mut file)
+        .take(CHUNK_SIZE as u64)
+        .read_to_end(&mut data)
+        .await
+        .map_err(io_error)?;
+
+    // the offset is within the file, so this can't underflow
+    let checksum = if total_size - offset <= data.len() as u64 {
+        let contents = fs::read(&path).await.map_err(io_error)?;
+        Some(files::checksum(&contents))
+    } else {
+        None
+    };
+    Ok(SuccessfulResponse::FileChunk(FileChunk {
+        total_size,
+        checksum,
+        data,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn files_survive_a_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let contents = (0..CHUNK_SIZE * 2 + 3).map(|i| i as u8).collect::<Vec<_>>();
+        let checksum = files::checksum(&contents);
+
+        for (i, chunk) in contents.chunks(CHUNK_SIZE).enumerate() {
+            assert!(
+                !path.exists(),
+                "file was put in place before the last chunk"
+            );
+            let request = PutFile {
+                path: path.clone(),
+                offset: (i * CHUNK_SIZE) as u64,
+                total_size: contents.len() as u64,
+                checksum: checksum.clone(),
+                data: chunk.to_vec(),
+            };
+            assert_eq!(put(request).await, Ok(SuccessfulResponse::Unit));
+        }
+
+        let mut received = Vec::new();
+        loop {
+            let request = GetFile {
+                path: path.clone(),
+                offset: received.len() as u64,
+            };
+            let Ok(SuccessfulResponse::FileChunk(chunk)) = get(request).await else {
+                panic!("expected a chunk");
+            };
+            received.extend(chunk.data);
+            if let Some(c) = chunk.checksum {
+                assert_eq!(c, checksum);
+                break;
+            }
+        }
+        assert_eq!(received, contents);
+    }
+
+    #[tokio::test]
+    async fn corrupted_files_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let request = PutFile {
+            path: path.clone(),
+            offset: 0,
+            total_size: 4,
+            checksum: files::checksum(b"data"),
+            data: b"date".to_vec(),
+        };
+        assert!(put(request).await.is_err());
+        assert!(!path.exists());
+        assert!(!partial(&path).unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn chunks_past_the_end_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let request = PutFile {
+            path: path.clone(),
+            offset: u64::MAX,
+            total_size: 4,
+            checksum: files::checksum(b"data"),
+            data: b"data".to_vec(),
+        };
+        assert!(put(request).await.is_err());
+        assert!(!partial(&path).unwrap().exists());
+    }
+}
@@ -1,11 +1,45 @@
+mod custom;
+mod exec;
+mod files;
 #[cfg(feature = "music-ctl")]
 pub mod music;
+mod system_info;
+pub mod update;
 
-use std::{os::unix::prelude::CommandExt, sync::Mutex, thread, time::Duration};
+use std::{
+    os::unix::prelude::CommandExt,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
 
-use spark_protocol::{Command, ErrorResponse, Response, SuccessfulResponse};
+use futures::{StreamExt, stream::BoxStream};
+use spark_protocol::{
+    Capabilities, Command, ErrorResponse, Response, SuccessfulResponse, config::SetConfig,
+    music::Subscription,
+};
 
-pub async fn rxtx(cmd: Command) -> Response {
+use crate::{config, metrics};
+
+/// The commands this daemon is able to handle.
+pub fn capabilities() -> Capabilities {
+    let capabilities = Capabilities::all();
+    #[cfg(not(feature = "music-ctl"))]
+    let capabilities = capabilities.without("Music").without("Subscribe");
+    capabilities
+}
+
+pub async fn rxtx(shared: config::Shared, cmd: Command) -> Response {
+    let kind = cmd.name();
+    let start = Instant::now();
+    let response = handle(shared, cmd).await;
+    metrics::commands_handled(kind).inc();
+    metrics::command_seconds(kind).inc_by(start.elapsed().as_secs_f64());
+    response
+}
+
+async fn handle(shared: config::Shared, cmd: Command) -> Response {
+    let config = shared.borrow().clone();
     match cmd {
         Command::Heartbeat => Ok(SuccessfulResponse::Unit),
         Command::Reload => {
@@ -15,15 +49,58 @@ pub async fn rxtx(cmd: Command) -> Response {
         #[cfg(feature = "music-ctl")]
         Command::Music(m) => music::handle(m).await,
         #[cfg(not(feature = "music-ctl"))]
-        Command::Music(_) => Err(ErrorResponse::RequestFailed(
-            "music control is not enabled on this machine".into(),
-        )),
+        Command::Music(_) => Err(ErrorResponse::Unsupported {
+            command: "Music".into(),
+            protocol_version: spark_protocol::PROTOCOL_VERSION,
+        }),
         Command::Version => Ok(SuccessfulResponse::Version(
             env!("CARGO_PKG_VERSION").into(),
         )),
+        Command::Handshake(remote) => {
+            tracing::info!(?remote, "handshake");
+            Ok(SuccessfulResponse::Handshake(capabilities()))
+        }
+        Command::Subscribe(_) => Err(ErrorResponse::RequestFailed(
+            "subscriptions need a dedicated connection".into(),
+        )),
+        Command::Exec(e) => exec::handle(&config, e).await,
+        Command::PutFile(put) => files::put(put).await,
+        Command::GetFile(get) => files::get(get).await,
+        Command::SystemInfo => system_info::handle().await,
+        Command::Update { version } => update::handle(&config, version).await,
+        Command::GetConfig => Ok(SuccessfulResponse::Config(config.redacted())),
+        Command::SetConfig(SetConfig { patch }) => {
+            match config::update(&shared, |current| {
+                current.apply_live(&current.patched(&patch)?)
+            }) {
+                Ok(config) => {
+                    tracing::info!("config changed");
+                    Ok(SuccessfulResponse::Config(config.redacted()))
+                }
+                Err(e) => Err(ErrorResponse::RequestFailed(format!("{e:#}"))),
+            }
+        }
+        Command::Status => Ok(SuccessfulResponse::Status(super::status::snapshot(&config))),
+        Command::Custom { name, args } => custom::handle(&config, name, args).await,
     }
 }
 
+pub fn subscribe(subscription: Subscription) -> BoxStream<'static, Response> {
+    #[cfg(feature = "music-ctl")]
+    return music::subscribe(subscription).boxed();
+    #[cfg(not(feature = "music-ctl"))]
+    return {
+        tracing::debug!(?subscription, "refusing subscription");
+        futures::stream::once(async {
+            Err(ErrorResponse::Unsupported {
+                command: "Subscribe".into(),
+                protocol_version: spark_protocol::PROTOCOL_VERSION,
+            })
+        })
+        .boxed()
+    };
+}
+
 pub fn reload() -> Result<impl FnOnce(), ErrorResponse> {
     static RELOADING: Mutex<()> = Mutex::new(());
     let exe = match std::env::current_exe() {
@@ -36,13 +113,28 @@ pub fn reload() -> Result<impl FnOnce(), ErrorResponse> {
     Ok(move || {
         thread::sleep(Duration::from_secs(1));
         let _guard = RELOADING.lock().unwrap();
+        if super::systemd::under_unit() && super::systemd::restarts_on_failure() {
+            tracing::info!("reloading spark daemon through systemd");
+            super::restart();
+            return;
+        }
+        // the pid stays the same, so systemd waits for the new daemon to say it's ready
+        super::systemd::reloading();
         tracing::info!(?exe, "realoading spark daemon");
-        let e = std::process::Command::new(exe).arg("daemon").exec();
+        // the same arguments, so flags like --config and --watch-config survive the reload
+        let e = std::process::Command::new(exe)
+            .args(std::env::args_os().skip(1))
+            .exec();
         tracing::error!(?e, "exec self failed");
-        if let Some(arg0) = std::env::args().next() {
-            let e = std::process::Command::new(arg0).arg("daemon").exec();
+        if let Some(arg0) = std::env::args_os().next() {
+            let e = std::process::Command::new(arg0)
+                .args(std::env::args_os().skip(1))
+                .exec();
             tracing::error!(?e, "exec arg0 failed");
         }
+        // this process keeps running, so the binary on disk should be the one it is
+        update::abandon();
+        super::systemd::notify("READY=1");
         drop(_guard)
     })
 }
@@ -7,18 +7,77 @@ use mlib::{
         self, SmartQueueOpts,
         event::{OwnedLibMpvEvent, PlayerEvent},
     },
-    playlist::PartialSearchResult,
+    playlist::{PartialSearchResult, Playlist},
     queue::Queue,
 };
-use spark_protocol::{ErrorResponse, music::Response as MusicResponse};
-use tokio::time::timeout;
+use spark_protocol::{
+    ErrorResponse,
+    music::{Player, Response as MusicResponse, Subscription},
+};
+use tokio::{sync::mpsc, time::timeout};
+
+/// Players that take longer than this to carry out a command are reported as stuck.
+const PLAYER_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn forward<E: std::fmt::Debug>(e: E) -> ErrorResponse {
     ErrorResponse::ForwardedError(format!("{e:?}"))
 }
 
-pub async fn wait_for_next_title(player: &players::PlayerLink) -> Result<String, ErrorResponse> {
-    let stream = player.subscribe().await.map_err(forward)?;
+/// Whether an error means there is no player socket to talk to.
+fn is_not_running(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+    )
+}
+
+/// The player a command is aimed at, so errors can say which one misbehaved.
+#[derive(Clone, Copy)]
+struct Aim<'s> {
+    index: Option<usize>,
+    username: Option<&'s str>,
+}
+
+impl Aim<'_> {
+    fn error(self, e: mlib::Error) -> ErrorResponse {
+        match e {
+            mlib::Error::Io(e) if is_not_running(&e) => self.no_player(),
+            e => forward(e),
+        }
+    }
+
+    fn no_player(self) -> ErrorResponse {
+        ErrorResponse::NoPlayer {
+            index: self.index,
+            username: self.username.map(str::to_owned),
+        }
+    }
+
+    fn timed_out(self) -> ErrorResponse {
+        ErrorResponse::PlayerTimeout {
+            index: self.index,
+            username: self.username.map(str::to_owned),
+            timeout: PLAYER_TIMEOUT,
+        }
+    }
+}
+
+async fn load_playlist() -> Result<Playlist, ErrorResponse> {
+    Playlist::load().await.map_err(|e| match e {
+        mlib::Error::Io(e) if e.kind() == io::ErrorKind::NotFound => {
+            ErrorResponse::PlaylistNotFound {
+                reason: e.to_string(),
+            }
+        }
+        e => forward(e),
+    })
+}
+
+async fn wait_for_next_title(
+    player: &players::PlayerLink,
+    aim: Aim<'_>,
+) -> Result<String, ErrorResponse> {
+    let stream = player.subscribe().await.map_err(|e| aim.error(e))?;
     async fn from_stream(
         stream: impl Stream<Item = io::Result<PlayerEvent>>,
     ) -> Result<Option<String>, mlib::Error> {
@@ -38,7 +97,9 @@ pub async fn wait_for_next_title(player: &players::PlayerLink) -> Result<String,
     let title = match timeout(Duration::from_secs(2), from_stream(stream)).await {
         Ok(Err(io_error)) => return Err(ErrorResponse::IoError(io_error.to_string())),
         Ok(Ok(Some(title))) => title,
-        Ok(Ok(None)) | Err(_ /*elapsed*/) => player.media_title().await.map_err(forward)?,
+        Ok(Ok(None)) | Err(_ /*elapsed*/) => {
+            player.media_title().await.map_err(|e| aim.error(e))?
+        }
     };
 
     Ok(title)
@@ -49,19 +110,32 @@ pub async fn handle(cmd: spark_protocol::music::MusicCmd) -> spark_protocol::Res
         Some(i) => &players::PlayerLink::of(i),
         None => players::PlayerLink::current(),
     };
-    let player = match cmd.username {
-        Some(u) => &player.linked_to(u),
+    let player = match &cmd.username {
+        Some(u) => &player.linked_to(u.clone()),
         None => player,
     };
+    let aim = Aim {
+        index: cmd.index,
+        username: cmd.username.as_deref(),
+    };
+    match timeout(PLAYER_TIMEOUT, run(cmd.command, player, aim)).await {
+        Ok(response) => response.map(Into::into),
+        Err(_elapsed) => Err(aim.timed_out()),
+    }
+}
 
-    // TODO: Io(NotFound) should be translated to "no players running for user {username}"
-    let response: Result<MusicResponse, ErrorResponse> = match cmd.command {
+async fn run(
+    command: spark_protocol::music::MusicCmdKind,
+    player: &players::PlayerLink,
+    aim: Aim<'_>,
+) -> Result<MusicResponse, ErrorResponse> {
+    match command {
         spark_protocol::music::MusicCmdKind::Frwd => {
             player
                 .change_file(players::Direction::Next)
                 .then(|_| async {
                     Ok(MusicResponse::Title {
-                        title: wait_for_next_title(player).await?,
+                        title: wait_for_next_title(player, aim).await?,
                     })
                 })
                 .await
@@ -71,7 +145,7 @@ pub async fn handle(cmd: spark_protocol::music::MusicCmd) -> spark_protocol::Res
                 .change_file(players::Direction::Prev)
                 .then(|_| async {
                     Ok(MusicResponse::Title {
-                        title: wait_for_next_title(player).await?,
+                        title: wait_for_next_title(player, aim).await?,
                     })
                 })
                 .await
@@ -81,7 +155,7 @@ pub async fn handle(cmd: spark_protocol::music::MusicCmd) -> spark_protocol::Res
                 .cycle_pause()
                 .then(|_| async {
                     Ok(MusicResponse::PlayState {
-                        paused: player.is_paused().await.map_err(forward)?,
+                        paused: player.is_paused().await.map_err(|e| aim.error(e))?,
                     })
                 })
                 .await
@@ -91,7 +165,7 @@ pub async fn handle(cmd: spark_protocol::music::MusicCmd) -> spark_protocol::Res
                 .change_volume(amount)
                 .then(|_| async {
                     Ok(MusicResponse::Volume {
-                        volume: player.volume().await.map_err(forward)?,
+                        volume: player.volume().await.map_err(|e| aim.error(e))?,
                     })
                 })
                 .await
@@ -100,7 +174,7 @@ pub async fn handle(cmd: spark_protocol::music::MusicCmd) -> spark_protocol::Res
             async {
                 let current = Queue::current(player, Default::default())
                     .await
-                    .map_err(forward)?;
+                    .map_err(|e| aim.error(e))?;
                 Ok(MusicResponse::Current { current })
             }
             .await
@@ -110,23 +184,33 @@ pub async fn handle(cmd: spark_protocol::music::MusicCmd) -> spark_protocol::Res
                 let item = match Link::from_str(&query) {
                     Ok(l) => Item::Link(l),
                     Err(_) => {
-                        let mut playlist =
-                            mlib::playlist::Playlist::load().await.map_err(forward)?;
-                        let song = handle_search_result(
-                            playlist.partial_name_search_mut(query.split_whitespace()),
-                        )
-                        .map_err(forward)?;
+                        let mut playlist = load_playlist().await?;
+                        let song = match playlist.partial_name_search_mut(query.split_whitespace())
+                        {
+                            PartialSearchResult::One(song) => Some(song),
+                            PartialSearchResult::None => None,
+                            PartialSearchResult::Many(matches) => {
+                                return Err(ErrorResponse::SearchAmbiguous {
+                                    query: query.clone(),
+                                    matches: matches.into_iter().map(|m| m.to_string()).collect(),
+                                });
+                            }
+                        };
                         match song {
                             Some(song) => Item::Link(song.delete().link.into()),
                             None if search => Item::Search(Search::new(query)),
-                            None => return Err(forward("song not in playlist")),
+                            None => {
+                                return Err(ErrorResponse::RequestFailed(format!(
+                                    "no song in the playlist matches {query:?}"
+                                )));
+                            }
                         }
                     }
                 };
                 let summary = player
                     .smart_queue(item, SmartQueueOpts { no_move: false })
                     .await
-                    .map_err(forward)?;
+                    .map_err(|e| aim.error(e))?;
                 Ok(MusicResponse::QueueSummary {
                     from: summary.from,
                     moved_to: summary.moved_to,
@@ -148,8 +232,8 @@ pub async fn handle(cmd: spark_protocol::music::MusicCmd) -> spark_protocol::Res
                 }
                 let queue = Queue::load(player, amount.unwrap_or(20))
                     .await
-                    .map_err(forward)?;
-                let playlist = mlib::playlist::Playlist::load().await.map_err(forward)?;
+                    .map_err(|e| aim.error(e))?;
+                let playlist = load_playlist().await?;
                 let (before, current, after) = join3(
                     stream::iter(queue.before())
                         .map(|i| i.item.fetch_item_title(&playlist).rust_pls())
@@ -173,23 +257,160 @@ pub async fn handle(cmd: spark_protocol::music::MusicCmd) -> spark_protocol::Res
             }
             .await
         }
-    };
-    response.map(Into::into)
+        spark_protocol::music::MusicCmdKind::Seek { seconds } => {
+            player
+                .seek(Duration::from_secs(seconds))
+                .await
+                .map_err(|e| aim.error(e))?;
+            let current = Queue::current(player, Default::default())
+                .await
+                .map_err(|e| aim.error(e))?;
+            Ok(MusicResponse::Position {
+                playback_time: current.playback_time,
+                duration: current.duration,
+            })
+        }
+        spark_protocol::music::MusicCmdKind::Shuffle => {
+            player.cycle_shuffle().await.map_err(|e| aim.error(e))?;
+            Ok(MusicResponse::Shuffle {
+                shuffled: player.is_shuffled().await.map_err(|e| aim.error(e))?,
+            })
+        }
+        spark_protocol::music::MusicCmdKind::Loop => {
+            player.cycle_loop().await.map_err(|e| aim.error(e))?;
+            Ok(MusicResponse::Loop {
+                looping: player.is_looping().await.map_err(|e| aim.error(e))?,
+            })
+        }
+        spark_protocol::music::MusicCmdKind::Remove { index } => {
+            player.queue_remove(index).await.map_err(|e| aim.error(e))?;
+            Ok(MusicResponse::Removed { index })
+        }
+        spark_protocol::music::MusicCmdKind::Move { from, to } => {
+            player
+                .queue_move(from, to)
+                .await
+                .map_err(|e| aim.error(e))?;
+            Ok(MusicResponse::Moved { from, to })
+        }
+        spark_protocol::music::MusicCmdKind::Clear => {
+            player.queue_clear().await.map_err(|e| aim.error(e))?;
+            Ok(MusicResponse::Cleared)
+        }
+        spark_protocol::music::MusicCmdKind::Jump { index } => {
+            player.queue_jump(index).await.map_err(|e| aim.error(e))?;
+            Ok(MusicResponse::Title {
+                title: wait_for_next_title(player, aim).await?,
+            })
+        }
+        spark_protocol::music::MusicCmdKind::ListPlayers => Ok(MusicResponse::Players {
+            players: list_players(aim.username).await,
+        }),
+    }
 }
 
-fn handle_search_result<T>(r: PartialSearchResult<T>) -> Result<Option<T>, String> {
-    use std::fmt::Write;
-    match r {
-        PartialSearchResult::One(t) => Ok(Some(t)),
-        PartialSearchResult::None => Ok(None),
-        PartialSearchResult::Many(too_many_matches) => {
-            let mut buf = String::from("too many matches:\n");
+/// Players are looked for up to this index.
+const MAX_PLAYERS: usize = 16;
 
-            for m in too_many_matches {
-                writeln!(buf, "  {m}").unwrap();
+/// Asks every index the commands can be aimed at whether there's a player there, the same way
+/// [`handle`] resolves the player of a command.
+async fn list_players(username: Option<&str>) -> Vec<Player> {
+    let players = (0..MAX_PLAYERS).map(|index| async move {
+        let player = players::PlayerLink::of(index);
+        let player = match username {
+            Some(u) => player.linked_to(u.to_owned()),
+            None => player,
+        };
+        let paused = match player.is_paused().await {
+            Ok(paused) => Some(paused),
+            Err(mlib::Error::Io(e)) if is_not_running(&e) => return None,
+            Err(e) => {
+                tracing::debug!(index, error = ?e, "player didn't report whether it's paused");
+                None
             }
+        };
+        // players with nothing loaded fail to report a title
+        let title = player.media_title().await.ok();
+        Some(Player {
+            index,
+            owner: username.map(str::to_owned),
+            title,
+            paused,
+        })
+    });
+    futures::future::join_all(players)
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
 
-            Err(buf)
+/// Streams the player events the subscription asked for, until the player goes away or nobody is
+/// listening anymore.
+pub fn subscribe(subscription: Subscription) -> impl Stream<Item = spark_protocol::Response> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let player = match subscription.index {
+            Some(i) => &players::PlayerLink::of(i),
+            None => players::PlayerLink::current(),
+        };
+        let player = match &subscription.username {
+            Some(u) => &player.linked_to(u.clone()),
+            None => player,
+        };
+        let aim = Aim {
+            index: subscription.index,
+            username: subscription.username.as_deref(),
+        };
+        let events = match player.subscribe().await {
+            Ok(events) => events,
+            Err(e) => {
+                let _ = tx.send(Err(aim.error(e))).await;
+                return;
+            }
+        };
+        tokio::pin!(events);
+        loop {
+            let event = tokio::select! {
+                _ = tx.closed() => break,
+                event = events.next() => event,
+            };
+            let event = match event {
+                Some(Ok(event)) => event.event,
+                Some(Err(e)) => {
+                    let _ = tx.send(Err(ErrorResponse::IoError(e.to_string()))).await;
+                    break;
+                }
+                None => break,
+            };
+            let OwnedLibMpvEvent::PropertyChange { name, change, .. } = event else {
+                continue;
+            };
+            let response = match &*name {
+                "media-title" => match change.into_string() {
+                    Ok(title) => Ok(MusicResponse::Title { title }),
+                    Err(_) => continue,
+                },
+                "pause" => player
+                    .is_paused()
+                    .await
+                    .map(|paused| MusicResponse::PlayState { paused })
+                    .map_err(|e| aim.error(e)),
+                "volume" => player
+                    .volume()
+                    .await
+                    .map(|volume| MusicResponse::Volume { volume })
+                    .map_err(|e| aim.error(e)),
+                _ => continue,
+            };
+            if response.as_ref().is_ok_and(|r| !subscription.wants(r)) {
+                continue;
+            }
+            if tx.send(response.map(Into::into)).await.is_err() {
+                break;
+            }
         }
-    }
+        tracing::debug!(?subscription, "subscription ended");
+    });
+    stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|r| (r, rx)) })
 }
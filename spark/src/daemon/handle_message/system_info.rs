@@ -0,0 +1,226 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use nix::sys::statvfs::statvfs;
+use spark_protocol::{
+    ErrorResponse, SuccessfulResponse,
+    system_info::{Battery, Disk, LoadAverage, Memory, SystemInfo},
+};
+
+pub async fn handle() -> spark_protocol::Response {
+    // statvfs can block for a long time on network mounts
+    match tokio::task::spawn_blocking(read).await {
+        Ok(Ok(info)) => Ok(SuccessfulResponse::SystemInfo(info)),
+        Ok(Err(e)) => Err(ErrorResponse::IoError(e.to_string())),
+        Err(e) => Err(ErrorResponse::ForwardedError(e.to_string())),
+    }
+}
+
+fn read() -> io::Result<SystemInfo> {
+    Ok(SystemInfo {
+        uptime: uptime()?,
+        load_average: load_average()?,
+        memory: memory()?,
+        disks: disks()?,
+        batteries: batteries(),
+        cpu_temperature: cpu_temperature(),
+    })
+}
+
+fn invalid(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("failed to parse {path}"),
+    )
+}
+
+fn uptime() -> io::Result<Duration> {
+    const PATH: &str = "/proc/uptime";
+    fs::read_to_string(PATH)?
+        .split_whitespace()
+        .next()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| invalid(PATH))
+}
+
+fn load_average() -> io::Result<LoadAverage> {
+    const PATH: &str = "/proc/loadavg";
+    let loadavg = fs::read_to_string(PATH)?;
+    let mut fields = loadavg.split_whitespace().map(str::parse);
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(Ok(one)), Some(Ok(five)), Some(Ok(fifteen))) => {
+            Ok(LoadAverage { one, five, fifteen })
+        }
+        _ => Err(invalid(PATH)),
+    }
+}
+
+fn memory() -> io::Result<Memory> {
+    const PATH: &str = "/proc/meminfo";
+    parse_meminfo(&fs::read_to_string(PATH)?).ok_or_else(|| invalid(PATH))
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<Memory> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|kb| kb.trim().strip_suffix("kB")?.trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+    Some(Memory {
+        total: field("MemTotal")?,
+        available: field("MemAvailable")?,
+    })
+}
+
+// the statvfs field types are narrower on 32 bit targets, like the pi zero
+#[allow(clippy::useless_conversion)]
+fn disks() -> io::Result<Vec<Disk>> {
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+    let mut seen = HashSet::new();
+    Ok(parse_mounts(&mounts)
+        // bind mounts and btrfs subvolumes show up as the same device mounted many times
+        .filter(|(device, _)| seen.insert(*device))
+        .filter_map(|(device, mount_point)| {
+            let stat = statvfs(&mount_point)
+                .inspect_err(|e| tracing::debug!(error = ?e, ?mount_point, "statvfs failed"))
+                .ok()?;
+            let block_size = u64::from(stat.fragment_size());
+            Some(Disk {
+                device: device.into(),
+                total: u64::from(stat.blocks()) * block_size,
+                available: u64::from(stat.blocks_available()) * block_size,
+                mount_point,
+            })
+        })
+        .collect())
+}
+
+/// The mounts backed by a block device, as `(device, mount point)`.
+fn parse_mounts(mounts: &str) -> impl Iterator<Item = (&str, PathBuf)> {
+    mounts.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let device = fields.next()?;
+        let mount_point = fields.next()?;
+        device
+            .starts_with("/dev/")
+            .then(|| (device, unescape(mount_point)))
+    })
+}
+
+/// Mount points have their whitespace and backslashes escaped as octal, like `\040` for a space.
+fn unescape(mount_point: &str) -> PathBuf {
+    let mut unescaped = String::with_capacity(mount_point.len());
+    let mut rest = mount_point;
+    while let Some(i) = rest.find('\\') {
+        unescaped.push_str(&rest[..i]);
+        rest = &rest[i..];
+        match rest
+            .get(1..4)
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok())
+        {
+            Some(c) => {
+                unescaped.push(char::from(c));
+                rest = &rest[4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped.into()
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_owned())
+}
+
+fn batteries() -> Vec<Battery> {
+    let Ok(supplies) = fs::read_dir("/sys/class/power_supply") else {
+        return vec![];
+    };
+    let mut batteries = supplies
+        .filter_map(Result::ok)
+        .filter_map(|supply| {
+            let path = supply.path();
+            if read_trimmed(&path.join("type"))? != "Battery" {
+                return None;
+            }
+            Some(Battery {
+                name: supply.file_name().to_string_lossy().into_owned(),
+                capacity: read_trimmed(&path.join("capacity"))?.parse().ok()?,
+                status: read_trimmed(&path.join("status")).unwrap_or_else(|| "Unknown".into()),
+            })
+        })
+        .collect::<Vec<_>>();
+    batteries.sort_by(|a, b| a.name.cmp(&b.name));
+    batteries
+}
+
+/// Reads the thermal zone that looks like the cpu's, or the first one if none do.
+fn cpu_temperature() -> Option<f64> {
+    let mut zones = fs::read_dir("/sys/class/thermal")
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|zone| {
+            zone.file_name()
+                .to_string_lossy()
+                .starts_with("thermal_zone")
+        })
+        .map(|zone| zone.path())
+        .collect::<Vec<_>>();
+    zones.sort();
+    let is_cpu = |zone: &&PathBuf| {
+        read_trimmed(&zone.join("type")).is_some_and(|kind| {
+            let kind = kind.to_lowercase();
+            ["cpu", "x86_pkg_temp", "soc"]
+                .iter()
+                .any(|name| kind.contains(name))
+        })
+    };
+    let zone = zones.iter().find(is_cpu).or(zones.first())?;
+    let millidegrees = read_trimmed(&zone.join("temp"))?.parse::<f64>().ok()?;
+    Some(millidegrees / 1000.)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn meminfo_is_in_kibibytes() {
+        let meminfo = "MemTotal:        3884376 kB\nMemFree:          151476 kB\nMemAvailable:    1797184 kB\n";
+        assert_eq!(
+            parse_meminfo(meminfo),
+            Some(Memory {
+                total: 3884376 * 1024,
+                available: 1797184 * 1024,
+            })
+        );
+    }
+
+    #[test]
+    fn only_block_devices_are_disks() {
+        let mounts = "\
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+/dev/mmcblk0p2 / ext4 rw,noatime 0 0
+tmpfs /run tmpfs rw,nosuid,nodev,size=1589352k,mode=755 0 0
+/dev/sda1 /mnt/external\\040drive vfat rw,relatime 0 0
+";
+        assert_eq!(
+            parse_mounts(mounts).collect::<Vec<_>>(),
+            [
+                ("/dev/mmcblk0p2", PathBuf::from("/")),
+                ("/dev/sda1", PathBuf::from("/mnt/external drive")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! Replacing the running binary with a build published on the backend.
+//!
+//! The new binary is swapped in next to the current one, which is kept around, once it has
+//! shown it can at least be executed. Until the new binary manages to connect to the backend a
+//! pending marker stays on disk, and if it is still there after
+//! [`Update::rollback_after_secs`](crate::config::Update) or the new binary keeps failing to
+//! start, the previous binary is put back.
+
+use std::{
+    env,
+    ffi::OsString,
+    fs, io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use common::{domain::spark_build::SparkBuild, net::AuthenticatedClient};
+use spark_protocol::{ErrorResponse, SuccessfulResponse, files};
+use tokio::io::AsyncWriteExt;
+
+use crate::config::Config;
+
+/// The target triple this binary was built for.
+pub const TARGET: &str = env!("TARGET");
+
+/// How many times an updated binary may be started without connecting to the backend before
+/// it is rolled back. Covers binaries that crash before the rollback timer fires.
+const MAX_START_ATTEMPTS: u32 = 3;
+
+/// How long a downloaded binary gets to print its version before it's considered broken.
+const SMOKE_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Paths {
+    exe: PathBuf,
+    /// Where the downloaded binary is written before being swapped in.
+    new: PathBuf,
+    /// The binary that was running before the update.
+    old: PathBuf,
+    /// Exists while an update hasn't been confirmed, holds how many times it was started.
+    pending: PathBuf,
+}
+
+impl Paths {
+    fn current() -> io::Result<Self> {
+        let exe = std::env::current_exe()?;
+        let Some(name) = exe.file_name() else {
+            return Err(io::Error::other(format!(
+                "{} is not a file path",
+                exe.display()
+            )));
+        };
+        let sibling = |suffix: &str| {
+            let mut sibling = OsString::from(".");
+            sibling.push(name);
+            sibling.push(suffix);
+            exe.with_file_name(sibling)
+        };
+        Ok(Self {
+            new: sibling(".new"),
+            old: sibling(".old"),
+            pending: sibling(".pending"),
+            exe,
+        })
+    }
+}
+
+pub async fn handle(config: &Config, version: String) -> spark_protocol::Response {
+    if version == env!("CARGO_PKG_VERSION") {
+        return Err(ErrorResponse::RequestFailed(format!(
+            "already running version {version}"
+        )));
+    }
+    // /proc/self/exe points to a deleted file once the binary is swapped, so the path to
+    // exec has to be resolved before that
+    let reload = super::reload()?;
+    match install(config, &version).await {
+        Ok(()) => {
+            tracing::info!(version, "update installed, reloading");
+            thread::spawn(reload);
+            Ok(SuccessfulResponse::Unit)
+        }
+        Err(e) => Err(ErrorResponse::RequestFailed(format!("{e:#}"))),
+    }
+}
+
+async fn install(config: &Config, version: &str) -> anyhow::Result<()> {
+    let paths = Paths::current().context("finding the running binary")?;
+    let client = AuthenticatedClient::try_from(config)?;
+
+    let build = client
+        .get(&format!("/spark/builds/{TARGET}/{version}/meta"))?
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<SparkBuild>()
+        .await
+        .context("fetching build metadata")?;
+    anyhow::ensure!(
+        build.version == version,
+        "the backend has version {} for {TARGET}, not {version}",
+        build.version
+    );
+    tracing::info!(?build, "downloading update");
+    let binary = client
+        .get(&format!("/spark/builds/{TARGET}/{version}"))?
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await
+        .context("downloading build")?;
+    anyhow::ensure!(
+        files::checksum(&binary) == build.checksum,
+        "checksum mismatch, the download was discarded"
+    );
+
+    let mut new = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o755)
+        .open(&paths.new)
+        .await
+        .with_context(|| format!("creating {}", paths.new.display()))?;
+    new.write_all(&binary).await?;
+    new.sync_all().await?;
+    drop(new);
+    if let Err(e) = smoke_test(&paths.new, version).await {
+        if let Err(e) = tokio::fs::remove_file(&paths.new).await {
+            tracing::warn!(error = ?e, "failed to remove the broken download");
+        }
+        return Err(e);
+    }
+
+    tokio::fs::copy(&paths.exe, &paths.old)
+        .await
+        .with_context(|| format!("backing up {}", paths.exe.display()))?;
+    tokio::fs::write(&paths.pending, "0").await?;
+    tokio::fs::rename(&paths.new, &paths.exe)
+        .await
+        .with_context(|| format!("replacing {}", paths.exe.display()))?;
+    Ok(())
+}
+
+/// Makes sure the downloaded binary runs on this machine, catching builds for the wrong target
+/// or linked against a libc that isn't here before they replace the running one.
+async fn smoke_test(new: &Path, version: &str) -> anyhow::Result<()> {
+    let output = tokio::process::Command::new(new)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(SMOKE_TEST_TIMEOUT, output)
+        .await
+        .context("the new binary took too long to print its version")?
+        .context("running the new binary")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "the new binary failed to print its version: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    let printed = String::from_utf8_lossy(&output.stdout);
+    anyhow::ensure!(
+        printed.split_whitespace().any(|word| word == version),
+        "the new binary says it's {}, not {version}",
+        printed.trim()
+    );
+    Ok(())
+}
+
+/// Called first thing when the daemon starts, before loading anything a new version could
+/// choke on. If this binary was just updated to and has already failed to start too many
+/// times, the previous binary is put back and executed in place of this process.
+pub fn check_pending() -> anyhow::Result<()> {
+    let paths = Paths::current()?;
+    let attempts = match fs::read_to_string(&paths.pending) {
+        Ok(attempts) => attempts.trim().parse::<u32>().unwrap_or(MAX_START_ATTEMPTS) + 1,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if attempts > MAX_START_ATTEMPTS {
+        tracing::error!(
+            attempts,
+            "updated binary keeps failing to start, rolling back"
+        );
+        restore(&paths)?;
+        // nothing was started yet, so the previous binary can take over as is
+        let e = std::process::Command::new(&paths.exe)
+            .args(env::args_os().skip(1))
+            .exec();
+        return Err(e).context("executing the previous binary");
+    }
+    fs::write(&paths.pending, attempts.to_string())?;
+    tracing::info!(attempts, "running an unconfirmed update");
+    Ok(())
+}
+
+/// Rolls back the update this binary was started as unless it [confirm]s it works in time.
+pub fn rollback_unless_confirmed(config: &Config) {
+    let Ok(paths) = Paths::current() else {
+        return;
+    };
+    if !paths.pending.exists() {
+        return;
+    }
+    let rollback_after = Duration::from_secs(config.update.rollback_after_secs);
+    tracing::info!(
+        ?rollback_after,
+        "update has to connect to the backend in time"
+    );
+    tokio::spawn(async move {
+        tokio::time::sleep(rollback_after).await;
+        if paths.pending.exists() {
+            tracing::error!("update failed to connect to the backend in time, rolling back");
+            if let Err(e) = rollback(&paths) {
+                tracing::error!(error = ?e, "failed to roll back update");
+            }
+        }
+    });
+}
+
+/// Marks the running binary as working, so it is no longer rolled back.
+pub fn confirm() {
+    let Ok(paths) = Paths::current() else {
+        return;
+    };
+    match fs::remove_file(&paths.pending) {
+        Ok(()) => tracing::info!(version = env!("CARGO_PKG_VERSION"), "update confirmed"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => tracing::warn!(error = ?e, "failed to confirm update"),
+    }
+}
+
+fn rollback(paths: &Paths) -> anyhow::Result<()> {
+    let reload = super::reload().map_err(|e| anyhow::anyhow!("{e:?}"))?;
+    restore(paths)?;
+    thread::spawn(reload);
+    Ok(())
+}
+
+/// Puts the previous binary back if the running one failed to hand over to an update it just
+/// installed, so that the next start doesn't pick up a binary that can't be executed.
+pub fn abandon() {
+    let Ok(paths) = Paths::current() else {
+        return;
+    };
+    if !paths.pending.exists() {
+        return;
+    }
+    match restore(&paths) {
+        Ok(()) => tracing::warn!("abandoned the update, the previous binary is back in place"),
+        Err(e) => tracing::error!(error = ?e, "failed to put the previous binary back"),
+    }
+}
+
+fn restore(paths: &Paths) -> io::Result<()> {
+    fs::rename(&paths.old, &paths.exe)?;
+    fs::remove_file(&paths.pending)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn paths_in(dir: &Path) -> Paths {
+        Paths {
+            exe: dir.join("spark"),
+            new: dir.join(".spark.new"),
+            old: dir.join(".spark.old"),
+            pending: dir.join(".spark.pending"),
+        }
+    }
+
+    #[test]
+    fn restoring_puts_back_the_previous_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = paths_in(dir.path());
+        fs::write(&paths.exe, "new").unwrap();
+        fs::write(&paths.old, "old").unwrap();
+        fs::write(&paths.pending, "1").unwrap();
+
+        restore(&paths).unwrap();
+
+        assert_eq!(fs::read_to_string(&paths.exe).unwrap(), "old");
+        assert!(!paths.old.exists());
+        assert!(!paths.pending.exists());
+    }
+
+    #[test]
+    fn backups_are_hidden_next_to_the_binary() {
+        let paths = Paths::current().unwrap();
+        let name = paths.exe.file_name().unwrap().to_string_lossy();
+        assert_eq!(paths.old.parent(), paths.exe.parent());
+        assert_eq!(
+            paths.old.file_name().unwrap().to_string_lossy(),
+            format!(".{name}.old")
+        );
+    }
+
+    #[tokio::test]
+    async fn binaries_that_cant_run_are_refused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let new = dir.path().join(".spark.new");
+        fs::write(&new, "#!/bin/sh\necho spark 1.2.3\n").unwrap();
+        fs::set_permissions(&new, fs::Permissions::from_mode(0o755)).unwrap();
+        smoke_test(&new, "1.2.3").await.unwrap();
+        assert!(smoke_test(&new, "1.2.4").await.is_err());
+
+        fs::write(&new, b"\x7fELF garbage").unwrap();
+        assert!(smoke_test(&new, "1.2.3").await.is_err());
+    }
+}
@@ -1,17 +1,112 @@
-use crate::config::Config;
+use crate::config::{self, Config};
 use anyhow::Context;
-use spark_protocol::{Command, client::ClientBuilder, server::ServerBuilder};
-use std::{future::Future, io, sync::Arc};
+use futures::{Stream, StreamExt};
+use nix::unistd::{Gid, Group, Uid, User};
+use spark_protocol::{
+    Command, ErrorResponse, client::ClientBuilder, music::Subscription, server::ServerBuilder,
+};
+use std::{collections::HashSet, future::Future, io, path::Path};
 
 use super::handle_message;
 
-pub async fn start(config: Arc<Config>) -> io::Result<impl Future<Output = ()>> {
+/// Commands only the user running the daemon may send.
+const OWNER_ONLY: &[&str] = &["Reload", "SetConfig"];
+
+/// Who may send what through the socket, resolved from [`config::Ipc`] when the daemon starts.
+#[derive(Debug)]
+struct Access {
+    owner: u32,
+    uids: HashSet<u32>,
+    groups: Vec<Group>,
+}
+
+impl Access {
+    fn new(owner: u32, ipc: &config::Ipc) -> Self {
+        let groups = ipc
+            .allowed_groups
+            .iter()
+            .filter_map(|name| match Group::from_name(name) {
+                Ok(Some(group)) => Some(group),
+                Ok(None) => {
+                    tracing::warn!(name, "allowed group does not exist");
+                    None
+                }
+                Err(e) => {
+                    tracing::warn!(name, error = ?e, "failed to look up allowed group");
+                    None
+                }
+            })
+            .collect();
+        Self {
+            owner,
+            uids: ipc.allowed_uids.clone(),
+            groups,
+        }
+    }
+
+    fn check(&self, uid: u32, gid: u32, command: &Command) -> Result<(), ErrorResponse> {
+        let allowed = if OWNER_ONLY.contains(&command.name()) {
+            uid == self.owner
+        } else {
+            uid == self.owner || self.uids.contains(&uid) || self.in_allowed_group(uid, gid)
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(ErrorResponse::PermissionDenied {
+                command: command.name().into(),
+                uid,
+            })
+        }
+    }
+
+    fn in_allowed_group(&self, uid: u32, gid: u32) -> bool {
+        if self.groups.is_empty() {
+            return false;
+        }
+        if self.groups.iter().any(|g| g.gid == Gid::from_raw(gid)) {
+            return true;
+        }
+        // supplementary groups aren't part of the peer credentials
+        let Ok(Some(user)) = User::from_uid(Uid::from_raw(uid)) else {
+            return false;
+        };
+        self.groups.iter().any(|g| g.mem.contains(&user.name))
+    }
+}
+
+pub async fn start(shared: config::Shared) -> io::Result<impl Future<Output = ()>> {
+    let config = shared.borrow().clone();
+    let access = Access::new(Uid::effective().as_raw(), &config.ipc);
+    tracing::debug!(?access, "ipc access");
     ServerBuilder::new()
         .with_path(config.ipc_socket_path.clone())
-        .serve(handle_message::rxtx)
+        // anyone on this machine can reach the socket, running programs, touching files and
+        // replacing the binary is only for admins going through the backend
+        .with_capabilities(
+            handle_message::capabilities()
+                .without("Exec")
+                .without("Custom")
+                .without("PutFile")
+                .without("GetFile")
+                .without("Update"),
+        )
+        .with_subscriptions(handle_message::subscribe)
+        .with_authorizer(move |peer, cmd| access.check(peer.uid(), peer.gid(), cmd))
+        .serve(move |cmd| handle_message::rxtx(shared.clone(), cmd))
         .await
 }
 
+/// Removes the socket the server was listening on, so clients don't try to reach a daemon that
+/// isn't there anymore.
+pub(super) async fn remove_socket(path: &Path) {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => tracing::debug!(?path, "removed ipc socket"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => tracing::warn!(?path, error = ?e, "failed to remove ipc socket"),
+    }
+}
+
 pub async fn send(cmd: &Command, config: Config) -> anyhow::Result<spark_protocol::Response> {
     ClientBuilder::new()
         .with_path(config.ipc_socket_path)
@@ -22,3 +117,68 @@ pub async fn send(cmd: &Command, config: Config) -> anyhow::Result<spark_protoco
         .await?
         .context("server shutdown")
 }
+
+pub async fn subscribe(
+    subscription: Subscription,
+    config: Config,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<spark_protocol::Response>>> {
+    Ok(ClientBuilder::new()
+        .with_path(config.ipc_socket_path)
+        .build()
+        .await
+        .context("starting client")?
+        .subscribe(subscription)
+        .await?
+        .map(Ok))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OWNER: u32 = 1000;
+    const FRIEND: u32 = 1001;
+    const STRANGER: u32 = 1002;
+
+    fn access() -> Access {
+        Access::new(
+            OWNER,
+            &config::Ipc {
+                allowed_uids: [FRIEND].into(),
+                allowed_groups: HashSet::new(),
+            },
+        )
+    }
+
+    #[test]
+    fn the_owner_can_send_anything() {
+        let access = access();
+        assert_eq!(access.check(OWNER, OWNER, &Command::Version), Ok(()));
+        assert_eq!(access.check(OWNER, OWNER, &Command::Reload), Ok(()));
+    }
+
+    #[test]
+    fn allowed_users_cannot_reload() {
+        let access = access();
+        assert_eq!(access.check(FRIEND, FRIEND, &Command::Version), Ok(()));
+        assert_eq!(
+            access.check(FRIEND, FRIEND, &Command::Reload),
+            Err(ErrorResponse::PermissionDenied {
+                command: "Reload".into(),
+                uid: FRIEND,
+            })
+        );
+    }
+
+    #[test]
+    fn everyone_else_is_refused() {
+        let access = access();
+        assert_eq!(
+            access.check(STRANGER, STRANGER, &Command::Version),
+            Err(ErrorResponse::PermissionDenied {
+                command: "Version".into(),
+                uid: STRANGER,
+            })
+        );
+    }
+}
@@ -1,20 +1,70 @@
-use crate::{config::Config, util::get_current_status};
-use common::net::{AuthenticatedClient, auth_client::UrlParseError};
+use super::status;
+use crate::{
+    config::{self, Heartbeat},
+    util::{get_current_status, get_ip_connections},
+};
+use common::{
+    domain::{
+        MachineStatus,
+        machine_status::{IpConnection, MachineStatusFull, PeerProbe},
+    },
+    net::{AuthenticatedClient, auth_client::UrlParseError},
+};
+use futures::future;
 use reqwest::StatusCode;
-use std::{future::Future, sync::Arc, time::Duration};
-use tokio::time::{sleep, timeout};
-use tracing::{debug, error, info_span, warn};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+use tracing::{debug, error, info, info_span, warn};
 
-pub fn start(config: Arc<Config>) -> Result<impl Future<Output = ()>, UrlParseError> {
-    let client = AuthenticatedClient::try_from(&*config)?;
+/// Wait after the first failed post, doubled for every failure after that.
+const BACKOFF_BASE: Duration = Duration::from_secs(5);
+
+/// How often the network interfaces are checked for changes between posts.
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a peer has to accept a connection before it's considered unreachable.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The port peers are reached on, machines on the same network are ssh'd into directly.
+const PROBE_PORT: u16 = 22;
+
+pub fn start(shared: &config::Shared) -> Result<impl Future<Output = ()>, UrlParseError> {
+    let mut config = shared.subscribe();
+    let client = AuthenticatedClient::try_from(&**config.borrow())?;
     Ok(async move {
+        let mut failures = 0;
+        let mut network = None;
+        let mut peers = Vec::new();
+        let mut last_probe = None::<Instant>;
         loop {
             let _span = info_span!("post machine status");
-            match get_current_status(&config).await {
-                Ok(status) => {
+            let current = config.borrow_and_update().clone();
+            let heartbeat = &current.heartbeat;
+            let posted = match get_current_status(&current).await {
+                Ok(mut status) => {
+                    let probe_interval = Duration::from_secs(heartbeat.probe_interval_secs);
+                    if !probe_interval.is_zero()
+                        && last_probe.is_none_or(|at| at.elapsed() >= probe_interval)
+                    {
+                        match probe_peers(&client, &status).await {
+                            Ok(probed) => peers = probed,
+                            Err(e) => warn!(error = ?e, "failed to probe peers"),
+                        }
+                        last_probe = Some(Instant::now());
+                    }
+                    status.peers = peers.clone();
                     debug!("posting machine status: {:#?}", status);
+                    network = Some(status.ip_connections.clone());
                     let result = timeout(
-                        Duration::from_secs(10),
+                        Duration::from_secs(heartbeat.timeout_secs),
                         client
                             .post("/machine/status")
                             .expect("building a request")
@@ -23,16 +73,139 @@ pub fn start(config: Arc<Config>) -> Result<impl Future<Output = ()>, UrlParseEr
                     )
                     .await;
                     match result {
-                        Ok(Ok(r)) if r.status() == StatusCode::OK => debug!("Post succeeded"),
-                        Ok(Ok(r)) => error!("Post request failed: {}", r.status()),
-                        Ok(Err(e)) => error!("Network request failed: {:?}", e),
-                        Err(_elapsed) => warn!("request timed out"),
+                        Ok(Ok(r)) if r.status() == StatusCode::OK => {
+                            debug!("Post succeeded");
+                            status::posted(r.status().as_u16(), true);
+                            true
+                        }
+                        Ok(Ok(r)) => {
+                            error!("Post request failed: {}", r.status());
+                            status::posted(r.status().as_u16(), false);
+                            false
+                        }
+                        Ok(Err(e)) => {
+                            error!("Network request failed: {:?}", e);
+                            status::post_failed(format!("network request failed: {e}"));
+                            false
+                        }
+                        Err(_elapsed) => {
+                            warn!("request timed out");
+                            status::post_failed("request timed out".into());
+                            false
+                        }
                     }
                 }
-                Err(e) => error!("Failed to obtain a machine status: {:?}", e),
+                Err(e) => {
+                    error!("Failed to obtain a machine status: {:?}", e);
+                    status::post_failed(format!("failed to obtain a machine status: {e:#}"));
+                    false
+                }
             };
+            failures = if posted { 0 } else { failures + 1 };
 
-            sleep(Duration::from_secs(60)).await;
+            let wait = next_post(heartbeat, failures, rand::random());
+            debug!(?wait, failures, "next post");
+            // the ssh port and default user are part of the status
+            tokio::select! {
+                () = sleep(wait) => {}
+                Ok(()) = config.changed() => debug!("config changed"),
+                () = network_change(&mut network) => info!("network changed"),
+            }
         }
     })
 }
+
+/// How long to wait before the next post. `random` is in `0..1` and picks the jitter.
+fn next_post(heartbeat: &Heartbeat, failures: u32, random: f64) -> Duration {
+    if failures > 0 {
+        let backoff = BACKOFF_BASE.saturating_mul(2u32.saturating_pow(failures - 1));
+        return backoff.min(Duration::from_secs(heartbeat.max_backoff_secs));
+    }
+    let interval = heartbeat.interval_secs as f64;
+    let jitter = heartbeat.jitter_secs.min(heartbeat.interval_secs) as f64;
+    Duration::from_secs_f64(interval + jitter * (2. * random - 1.))
+}
+
+/// Measures how long it takes to connect to every other machine behind the same NAT.
+async fn probe_peers(
+    client: &AuthenticatedClient,
+    me: &MachineStatus,
+) -> anyhow::Result<Vec<PeerProbe>> {
+    let statuses = client
+        .get("/machine/status")?
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<HashMap<String, MachineStatusFull>>()
+        .await?;
+    let probes = statuses
+        .into_values()
+        .filter(|peer| peer.hostname != me.hostname && peer.share_nat(me))
+        .filter_map(|peer| Some((peer.preferred_ip()?, peer.fields.hostname)))
+        .map(|(ip, hostname)| async move {
+            let rtt = probe((ip, PROBE_PORT).into()).await;
+            debug!(%hostname, %ip, ?rtt, "probed peer");
+            PeerProbe { hostname, rtt }
+        });
+    Ok(future::join_all(probes).await)
+}
+
+async fn probe(addr: SocketAddr) -> Option<Duration> {
+    let start = Instant::now();
+    match timeout(PROBE_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Some(start.elapsed()),
+        Ok(Err(e)) => {
+            debug!(%addr, error = ?e, "peer refused the probe");
+            None
+        }
+        Err(_elapsed) => None,
+    }
+}
+
+/// Resolves once the ip connections differ from `last`, so a machine that moved to another
+/// network is reachable again without waiting for the next post.
+async fn network_change(last: &mut Option<Vec<IpConnection>>) {
+    loop {
+        sleep(NETWORK_CHECK_INTERVAL).await;
+        let now = match get_ip_connections().await {
+            Ok(now) => now,
+            Err(e) => {
+                debug!(error = ?e, "failed to check the network");
+                continue;
+            }
+        };
+        match last.replace(now) {
+            Some(previous) if Some(&previous) != last.as_ref() => return,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn failures_back_off_exponentially_up_to_the_cap() {
+        let heartbeat = Heartbeat {
+            max_backoff_secs: 30,
+            ..Default::default()
+        };
+        let waits = (1..=5)
+            .map(|failures| next_post(&heartbeat, failures, 0.5).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(waits, [5, 10, 20, 30, 30]);
+    }
+
+    #[test]
+    fn jitter_stays_around_the_interval() {
+        let heartbeat = Heartbeat {
+            interval_secs: 60,
+            jitter_secs: 5,
+            ..Default::default()
+        };
+        assert_eq!(next_post(&heartbeat, 0, 0.).as_secs(), 55);
+        assert_eq!(next_post(&heartbeat, 0, 0.5).as_secs(), 60);
+        assert!(next_post(&heartbeat, 0, 0.999) <= Duration::from_secs(65));
+    }
+}
@@ -4,32 +4,168 @@ mod handle_message;
 pub(crate) mod ipc;
 pub(crate) mod machine_status;
 pub(crate) mod persistent_conn;
+mod status;
+mod systemd;
 
+use anyhow::Context;
 use futures::future;
+use tokio::{
+    net::TcpListener,
+    signal::unix::{SignalKind, signal},
+    sync::{Notify, watch},
+};
+use tokio_util::sync::CancellationToken;
 
-use crate::config::Config;
-use std::sync::Arc;
+use crate::config::{self, Config};
+use std::{sync::Arc, time::Duration};
 
-pub async fn run_all(config: Config) -> anyhow::Result<()> {
-    let config = Arc::new(config);
-    let ctrl_c = async {
-        if let Err(e) = tokio::signal::ctrl_c().await {
-            tracing::error!(?e, "failed to setup ctrl c handler");
-            std::future::pending().await
+pub use handle_message::update::check_pending;
+
+/// How often the config file is checked for changes, when it's being watched.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the background tasks get to wind down once the daemon is told to stop.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+static RESTART: Notify = Notify::const_new();
+
+/// Why the daemon stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// It was told to stop.
+    Stopped,
+    /// It should be started again, by whoever started it.
+    Restart,
+    /// The background tasks returned on their own.
+    Failed,
+}
+
+impl Exit {
+    /// Anything but a clean stop is a failure, so units with `Restart=on-failure` start the
+    /// daemon again.
+    pub fn code(self) -> i32 {
+        match self {
+            Self::Stopped => 0,
+            Self::Restart => 75,
+            Self::Failed => 1,
         }
-    };
-    let persistent_conn = persistent_conn::start(config.clone());
-    let ipc = ipc::start(config.clone()).await?;
-    let machine_status = machine_status::start(config.clone())?;
-    let background_tasks = future::join3(persistent_conn, ipc, machine_status);
-
-    tokio::select! {
-        () = ctrl_c => {
-            tracing::info!("shutting down");
-        },
+    }
+}
+
+/// Stops the daemon with [`Exit::Restart`].
+pub(crate) fn restart() {
+    RESTART.notify_one();
+}
+
+/// Runs the daemon until it's told to stop. `SIGHUP` applies the config `reload_config` loads,
+/// and if `watch_config` is set it's also polled and changes to it are applied without being
+/// asked to.
+pub async fn run_all<F>(
+    config: Config,
+    reload_config: F,
+    watch_config: bool,
+) -> anyhow::Result<Exit>
+where
+    F: Fn() -> anyhow::Result<Config> + Clone + Send + 'static,
+{
+    status::started();
+    handle_message::update::rollback_unless_confirmed(&config);
+    // before anything is measured, metrics created earlier aren't registered
+    if let Some(metrics) = &config.metrics {
+        let listener = TcpListener::bind((metrics.address, metrics.port))
+            .await
+            .context("binding the metrics listener")?;
+        tracing::info!(addr = ?listener.local_addr(), "serving metrics");
+        let endpoint = common::telemetry::metrics::start_metrics_endpoint("spark", listener);
+        tokio::spawn(endpoint.worker);
+    }
+    let config = Arc::new(config);
+    let ipc_socket_path = config.ipc_socket_path.clone();
+    let shared = watch::Sender::new(config);
+    if watch_config {
+        tokio::spawn(self::watch_config(shared.clone(), reload_config.clone()));
+    }
+    let shutdown = CancellationToken::new();
+    let mut persistent_conn =
+        tokio::spawn(persistent_conn::start(shared.clone(), shutdown.clone()));
+    let ipc = ipc::start(shared.clone()).await?;
+    let machine_status = machine_status::start(&shared)?;
+    let background_tasks = future::join3(&mut persistent_conn, ipc, machine_status);
+    systemd::notify("READY=1");
+    tokio::spawn(systemd::watchdog());
+
+    let exit = tokio::select! {
+        exit = signals(&shared, reload_config) => exit?,
         _ = background_tasks => {
             tracing::warn!("all background tasks returned");
+            Exit::Failed
+        }
+    };
+    tracing::info!(?exit, "shutting down");
+    systemd::notify("STOPPING=1");
+    shutdown.cancel();
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, persistent_conn).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(e))) => tracing::warn!(error = ?e, "persistent connection failed to close"),
+        Ok(Err(e)) => tracing::warn!(error = ?e, "persistent connection task panicked"),
+        Err(_elapsed) => tracing::warn!("persistent connection took too long to close"),
+    }
+    ipc::remove_socket(&ipc_socket_path).await;
+    Ok(exit)
+}
+
+/// Resolves once the daemon should stop, applying the config on every `SIGHUP` until then.
+async fn signals<F>(shared: &config::Shared, reload_config: F) -> anyhow::Result<Exit>
+where
+    F: Fn() -> anyhow::Result<Config>,
+{
+    let mut terminate = signal(SignalKind::terminate()).context("listening for SIGTERM")?;
+    let mut interrupt = signal(SignalKind::interrupt()).context("listening for SIGINT")?;
+    let mut hangup = signal(SignalKind::hangup()).context("listening for SIGHUP")?;
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => return Ok(Exit::Stopped),
+            _ = interrupt.recv() => return Ok(Exit::Stopped),
+            () = RESTART.notified() => return Ok(Exit::Restart),
+            _ = hangup.recv() => {
+                tracing::info!("SIGHUP, reloading config");
+                systemd::reloading();
+                match reload_config() {
+                    Ok(loaded) => apply_file(shared, &loaded),
+                    Err(e) => tracing::warn!(error = ?e, "failed to reload config"),
+                }
+                systemd::notify("READY=1");
+            }
         }
     }
-    Ok(())
+}
+
+async fn watch_config<F>(shared: config::Shared, reload_config: F)
+where
+    F: Fn() -> anyhow::Result<Config>,
+{
+    // only changes to the file are applied, so they don't undo those made with SetConfig
+    let mut last = Config::clone(&shared.borrow());
+    loop {
+        tokio::time::sleep(CONFIG_POLL_INTERVAL).await;
+        let loaded = match reload_config() {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                tracing::warn!(error = ?e, "failed to reload config");
+                continue;
+            }
+        };
+        if loaded == last {
+            continue;
+        }
+        apply_file(&shared, &loaded);
+        last = loaded;
+    }
+}
+
+fn apply_file(shared: &config::Shared, loaded: &Config) {
+    match config::update(shared, |current| current.apply_live(loaded)) {
+        Ok(_) => tracing::info!("config file changed, applied"),
+        Err(e) => tracing::warn!(error = ?e, "config file changed, but can't be applied"),
+    }
 }
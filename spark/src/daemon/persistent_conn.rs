@@ -1,36 +1,63 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::Context;
-use common::{domain::Hostname, net::AuthenticatedClient, ws};
-use futures::FutureExt;
+use common::{
+    domain::Hostname,
+    net::{AuthenticatedClient, ReadJsonLinesExt},
+    ws,
+};
+use futures::{FutureExt, Stream, StreamExt, TryStreamExt, stream};
 use rust_socketio::{
     Payload,
     asynchronous::{Client, ClientBuilder},
 };
+use serde::de::DeserializeOwned;
 use serde_json::json;
-use spark_protocol::music::MusicCmdKind;
+use spark_protocol::{
+    Capabilities, SuccessfulResponse,
+    files::{self, CHUNK_SIZE, GetFile, MAX_FILE_SIZE, PutFile},
+    music::{MusicCmdKind, Subscription},
+    relay::{self, Broadcast, BroadcastResponse, SubscriptionId},
+};
+use tokio::{io::BufReader, task::AbortHandle};
+use tokio_util::{io::StreamReader, sync::CancellationToken};
 
-use crate::{config::Config, util::get_hostname};
+use crate::{
+    config::{self, Config},
+    util::get_hostname,
+};
 
-use super::handle_message;
+use super::{handle_message, status};
 
-#[tracing::instrument(skip(socket))]
-async fn handler(payload: Payload, socket: Client, ack: i32) {
-    let [command]: [spark_protocol::Command; 1] = match payload {
-        Payload::Text(values) => match serde_json::from_value(serde_json::Value::Array(values)) {
-            Ok(v) => v,
-            Err(e) => {
-                tracing::error!(error = ?e, "invalid command sent from server");
-                return;
-            }
-        },
+fn from_payload<T: DeserializeOwned>(payload: Payload) -> serde_json::Result<T> {
+    match payload {
+        Payload::Text(values) => {
+            serde_json::from_value::<[T; 1]>(serde_json::Value::Array(values)).map(|[t]| t)
+        }
         Payload::Binary(_) => panic!("unexpected bytes"),
         #[allow(deprecated)]
         Payload::String(_) => panic!("Payload::String panicked"),
+    }
+}
+
+#[tracing::instrument(skip(socket, config))]
+async fn handler(payload: Payload, socket: Client, ack: i32, config: config::Shared) {
+    let command: spark_protocol::Command = match from_payload(payload) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!(error = ?e, "invalid command sent from server");
+            return;
+        }
     };
 
     tracing::info!(?command, "received command");
-    let response = handle_message::rxtx(command).await;
+    let response = handle_message::rxtx(config, command).await;
 
     let e = socket
         .ack(ack, serde_json::to_string(&response).unwrap())
@@ -40,51 +67,254 @@ async fn handler(payload: Payload, socket: Client, ack: i32) {
     }
 }
 
-#[tracing::instrument(skip(token))]
-async fn run(config: &Config, hostname: &Hostname, token: uuid::Uuid) -> anyhow::Result<()> {
+async fn handshake(payload: Payload, _socket: Client) {
+    match from_payload::<Capabilities>(payload) {
+        Ok(server) => tracing::info!(?server, "handshake with server complete"),
+        Err(e) => tracing::warn!(error = ?e, "invalid handshake sent from server"),
+    }
+}
+
+type Subscriptions = Arc<Mutex<HashMap<SubscriptionId, AbortHandle>>>;
+
+#[tracing::instrument(skip(socket, subscriptions))]
+async fn on_subscribe(payload: Payload, socket: Client, subscriptions: Subscriptions) {
+    let relay::Subscribe { id, subscription } = match from_payload(payload) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!(error = ?e, "invalid subscription sent from server");
+            return;
+        }
+    };
+    tracing::info!(id, ?subscription, "subscribing");
+    // holding the lock makes sure the task can't remove itself before it's inserted
+    let mut running = subscriptions.lock().unwrap();
+    let task = tokio::spawn({
+        let subscriptions = subscriptions.clone();
+        async move {
+            let mut events = handle_message::subscribe(subscription);
+            while let Some(response) = events.next().await {
+                let event = relay::Event { id, response };
+                if let Err(e) = socket
+                    .emit(ws::EVENT, serde_json::to_string(&event).unwrap())
+                    .await
+                {
+                    tracing::error!(id, error = ?e, "failed to relay event, dropping subscription");
+                    break;
+                }
+            }
+            subscriptions.lock().unwrap().remove(&id);
+        }
+    });
+    running.insert(id, task.abort_handle());
+}
+
+async fn on_unsubscribe(payload: Payload, subscriptions: Subscriptions) {
+    match from_payload::<relay::Unsubscribe>(payload) {
+        Ok(relay::Unsubscribe { id }) => {
+            tracing::info!(id, "unsubscribing");
+            if let Some(task) = subscriptions.lock().unwrap().remove(&id) {
+                task.abort();
+            }
+        }
+        Err(e) => tracing::warn!(error = ?e, "invalid unsubscribe sent from server"),
+    }
+}
+
+#[tracing::instrument(skip(shared, token, shutdown))]
+async fn run(
+    shared: &config::Shared,
+    hostname: &Hostname,
+    token: uuid::Uuid,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<()> {
+    let config = shared.borrow().clone();
+    let subscriptions = Subscriptions::default();
     let socket = ClientBuilder::new(format!("{}?h={}", config.backend_domain, hostname))
-        .auth(json! {{ "token": token.to_string() }})
+        .auth(json! {{
+            "token": token.to_string(),
+            "capabilities": handle_message::capabilities(),
+            "tags": config.tags,
+        }})
         .namespace(ws::NS)
-        .on_with_ack(ws::COMMAND, |payload, socket, ack| {
-            handler(payload, socket, ack).boxed()
+        .on_with_ack(ws::COMMAND, {
+            let shared = shared.clone();
+            move |payload, socket, ack| handler(payload, socket, ack, shared.clone()).boxed()
+        })
+        .on(ws::HANDSHAKE, |payload, socket| {
+            handshake(payload, socket).boxed()
+        })
+        .on(ws::SUBSCRIBE, {
+            let subscriptions = subscriptions.clone();
+            move |payload, socket| on_subscribe(payload, socket, subscriptions.clone()).boxed()
+        })
+        .on(ws::UNSUBSCRIBE, {
+            let subscriptions = subscriptions.clone();
+            move |payload, _| on_unsubscribe(payload, subscriptions.clone()).boxed()
         })
+        .on("open", |_, _| async { status::connected() }.boxed())
+        .on("close", |_, _| async { status::disconnected(None) }.boxed())
         .on("error", |err, _| {
-            async move { tracing::error!(error = ?err, "socket io error") }.boxed()
+            async move {
+                tracing::error!(error = ?err, "socket io error");
+                status::disconnected(Some(format!("{err:?}")));
+            }
+            .boxed()
         })
         .connect()
         .await
         .context("failed to connect to ws endpoint")?;
+    status::connected();
+    handle_message::update::confirm();
 
-    std::future::pending::<()>().await;
+    shutdown.cancelled().await;
+    tracing::info!("closing ws persistent connection");
+    for (_, task) in subscriptions.lock().unwrap().drain() {
+        task.abort();
+    }
     socket.disconnect().await?;
     drop(socket);
+    status::disconnected(None);
     Ok(())
 }
 
-pub(super) async fn start(config: Arc<Config>) -> anyhow::Result<()> {
-    let hostname = get_hostname(&config).await?;
-    loop {
+/// Keeps a connection to the backend up until `shutdown` is cancelled, then disconnects from it.
+pub(super) async fn start(
+    shared: config::Shared,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let (hostname, token) = {
+        let config = shared.borrow().clone();
+        (get_hostname(&config).await?, config.token)
+    };
+    while !shutdown.is_cancelled() {
         tracing::info!("starting ws persistent connection");
-        if let Err(e) = run(&config, &hostname, config.token).await {
+        if let Err(e) = run(&shared, &hostname, token, &shutdown).await {
             tracing::error!(?e, "persistent ws connection dropped");
+            status::disconnected(Some(format!("{e:#}")));
+        }
+        tokio::select! {
+            () = tokio::time::sleep(Duration::from_secs(1)) => {}
+            () = shutdown.cancelled() => {}
         }
-        tokio::time::sleep(Duration::from_secs(1)).await;
     }
+    Ok(())
 }
 
 pub async fn send(
     config: Config,
     hostname: Hostname,
     command: spark_protocol::Command,
+) -> anyhow::Result<spark_protocol::Response> {
+    send_with(
+        &AuthenticatedClient::try_from(&config)?,
+        &hostname,
+        &command,
+    )
+    .await
+}
+
+async fn send_with(
+    client: &AuthenticatedClient,
+    hostname: &Hostname,
+    command: &spark_protocol::Command,
 ) -> anyhow::Result<spark_protocol::Response> {
     send_impl(
-        AuthenticatedClient::try_from(&config)?
+        client
             .post(&format!("/persistent-connections/ws/send/{hostname}"))?
-            .json(&command),
+            .json(command),
     )
     .await
 }
 
+pub async fn broadcast(config: Config, broadcast: Broadcast) -> anyhow::Result<BroadcastResponse> {
+    let resp = AuthenticatedClient::try_from(&config)?
+        .post("/persistent-connections/ws/broadcast")?
+        .json(&broadcast)
+        .send()
+        .await
+        .context("sending request to ws/persistent-connections/broadcast")?;
+    error_for_status(resp)
+        .await?
+        .json()
+        .await
+        .context("deserializing responses")
+}
+
+/// Copies `local` to `remote` on `hostname`, one chunk at a time.
+pub async fn put_file(
+    config: Config,
+    hostname: Hostname,
+    local: &Path,
+    remote: PathBuf,
+) -> anyhow::Result<spark_protocol::Response> {
+    let contents = tokio::fs::read(local)
+        .await
+        .with_context(|| format!("reading {}", local.display()))?;
+    anyhow::ensure!(
+        contents.len() as u64 <= MAX_FILE_SIZE,
+        "{} is too big, at most {MAX_FILE_SIZE} bytes can be transferred",
+        local.display()
+    );
+    let client = AuthenticatedClient::try_from(&config)?;
+    let checksum = files::checksum(&contents);
+    let mut offset = 0;
+    // empty files are still sent as one empty chunk
+    loop {
+        let end = (offset + CHUNK_SIZE).min(contents.len());
+        let put = PutFile {
+            path: remote.clone(),
+            offset: offset as u64,
+            total_size: contents.len() as u64,
+            checksum: checksum.clone(),
+            data: contents[offset..end].to_vec(),
+        };
+        let response = send_with(&client, &hostname, &put.into()).await?;
+        if response.is_err() || end == contents.len() {
+            return Ok(response);
+        }
+        offset = end;
+    }
+}
+
+/// Copies `remote` on `hostname` to `local`, one chunk at a time.
+pub async fn get_file(
+    config: Config,
+    hostname: Hostname,
+    remote: PathBuf,
+    local: &Path,
+) -> anyhow::Result<spark_protocol::Response> {
+    let client = AuthenticatedClient::try_from(&config)?;
+    let mut contents = Vec::new();
+    let checksum = loop {
+        let get = GetFile {
+            path: remote.clone(),
+            offset: contents.len() as u64,
+        };
+        let chunk = match send_with(&client, &hostname, &get.into()).await? {
+            Ok(SuccessfulResponse::FileChunk(chunk)) => chunk,
+            Ok(response) => anyhow::bail!("unexpected response: {response:?}"),
+            Err(e) => return Ok(Err(e)),
+        };
+        anyhow::ensure!(
+            chunk.total_size <= MAX_FILE_SIZE,
+            "remote file is too big, at most {MAX_FILE_SIZE} bytes can be transferred"
+        );
+        contents.extend_from_slice(&chunk.data);
+        if let Some(checksum) = chunk.checksum {
+            break checksum;
+        }
+        anyhow::ensure!(!chunk.data.is_empty(), "remote file shrunk while copying");
+    };
+    anyhow::ensure!(
+        files::checksum(&contents) == checksum,
+        "checksum mismatch, the remote file might have been changed while copying"
+    );
+    tokio::fs::write(local, contents)
+        .await
+        .with_context(|| format!("writing {}", local.display()))?;
+    Ok(Ok(SuccessfulResponse::Unit))
+}
+
 pub async fn send_to_session(
     config: Config,
     session: String,
@@ -98,15 +328,46 @@ pub async fn send_to_session(
     .await
 }
 
+pub async fn subscribe(
+    config: Config,
+    hostname: Hostname,
+    subscription: Subscription,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<spark_protocol::Response>>> {
+    let resp = AuthenticatedClient::try_from(&config)?
+        .post(&format!("/persistent-connections/ws/subscribe/{hostname}"))?
+        .json(&subscription)
+        .send()
+        .await
+        .context("sending request to ws/persistent-connections/subscribe")?;
+    let body = error_for_status(resp)
+        .await?
+        .bytes_stream()
+        .map_err(io::Error::other);
+    let events = BufReader::new(StreamReader::new(Box::pin(body)));
+    Ok(stream::try_unfold(events, |mut events| async move {
+        Ok(events
+            .recv::<spark_protocol::Response>()
+            .await
+            .context("receiving event")?
+            .map(|event| (event, events)))
+    }))
+}
+
 async fn send_impl(request: reqwest::RequestBuilder) -> anyhow::Result<spark_protocol::Response> {
     let resp = request
         .send()
         .await
         .context("sending request to ws/persistent-connections/send")?;
+    error_for_status(resp)
+        .await?
+        .json::<spark_protocol::Response>()
+        .await
+        .context("deserializing response")
+}
+
+async fn error_for_status(resp: reqwest::Response) -> anyhow::Result<reqwest::Response> {
     if resp.status().is_success() {
-        resp.json::<spark_protocol::Response>()
-            .await
-            .context("deserializing response")
+        Ok(resp)
     } else {
         let error = resp
             .error_for_status_ref()
@@ -0,0 +1,118 @@
+//! What the background tasks report about themselves, for [`spark_protocol::Command::Status`].
+
+use std::{
+    sync::{LazyLock, Mutex},
+    time::Instant,
+};
+
+use spark_protocol::status::{DaemonStatus, MachineStatusStatus, PersistentConnStatus};
+
+use crate::{config::Config, metrics};
+
+struct PersistentConn {
+    connected: bool,
+    since: Option<Instant>,
+    reconnects: u64,
+    last_error: Option<String>,
+}
+
+struct MachineStatus {
+    last_success: Option<Instant>,
+    last_status_code: Option<u16>,
+    failures: u32,
+    last_error: Option<String>,
+}
+
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+static PERSISTENT_CONN: Mutex<PersistentConn> = Mutex::new(PersistentConn {
+    connected: false,
+    since: None,
+    reconnects: 0,
+    last_error: None,
+});
+static MACHINE_STATUS: Mutex<MachineStatus> = Mutex::new(MachineStatus {
+    last_success: None,
+    last_status_code: None,
+    failures: 0,
+    last_error: None,
+});
+
+/// Marks the daemon as started, for its uptime.
+pub fn started() {
+    LazyLock::force(&STARTED);
+}
+
+pub fn connected() {
+    let mut conn = PERSISTENT_CONN.lock().unwrap();
+    if conn.connected {
+        return;
+    }
+    if conn.since.is_some() {
+        conn.reconnects += 1;
+        metrics::persistent_conn_reconnects().inc();
+    }
+    conn.connected = true;
+    conn.since = Some(Instant::now());
+}
+
+pub fn disconnected(error: Option<String>) {
+    let mut conn = PERSISTENT_CONN.lock().unwrap();
+    if conn.connected {
+        conn.connected = false;
+        conn.since = Some(Instant::now());
+    }
+    if error.is_some() {
+        conn.last_error = error;
+    }
+}
+
+/// Records a post of the machine status the backend answered, successfully or not.
+pub fn posted(status_code: u16, success: bool) {
+    let mut status = MACHINE_STATUS.lock().unwrap();
+    status.last_status_code = Some(status_code);
+    if success {
+        status.last_success = Some(Instant::now());
+        status.failures = 0;
+    } else {
+        status.failures += 1;
+        status.last_error = Some(format!("backend answered with {status_code}"));
+        metrics::heartbeat_failures().inc();
+    }
+}
+
+/// Records a post of the machine status that never got an answer.
+pub fn post_failed(error: String) {
+    let mut status = MACHINE_STATUS.lock().unwrap();
+    status.failures += 1;
+    status.last_error = Some(error);
+    metrics::heartbeat_failures().inc();
+}
+
+pub fn snapshot(config: &Config) -> DaemonStatus {
+    let now = Instant::now();
+    let persistent_conn = {
+        let conn = PERSISTENT_CONN.lock().unwrap();
+        PersistentConnStatus {
+            connected: conn.connected,
+            since: conn.since.map(|since| now - since),
+            reconnects: conn.reconnects,
+            last_error: conn.last_error.clone(),
+        }
+    };
+    let machine_status = {
+        let status = MACHINE_STATUS.lock().unwrap();
+        MachineStatusStatus {
+            last_success: status.last_success.map(|at| now - at),
+            last_status_code: status.last_status_code,
+            failures: status.failures,
+            last_error: status.last_error.clone(),
+        }
+    };
+    DaemonStatus {
+        version: env!("CARGO_PKG_VERSION").into(),
+        uptime: now - *STARTED,
+        ipc_socket_path: config.ipc_socket_path.clone(),
+        persistent_conn,
+        machine_status,
+    }
+}
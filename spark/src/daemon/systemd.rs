@@ -0,0 +1,154 @@
+//! The parts of systemd's service protocol the daemon speaks, see `sd_notify(3)`.
+//!
+//! Everything here is a no-op when the daemon isn't running under a unit.
+
+use std::{env, fs, io, os::unix::net::UnixDatagram, process::Command, time::Duration};
+
+use nix::time::{ClockId, clock_gettime};
+
+/// Whether the daemon was started by systemd as (part of) a unit.
+pub fn under_unit() -> bool {
+    env::var_os("INVOCATION_ID").is_some()
+}
+
+/// Whether systemd starts the unit again when the daemon exits with a failure, which is how
+/// [`Exit::Restart`](super::Exit) hands the daemon over to it. Units that can't be asked about
+/// are assumed not to.
+pub fn restarts_on_failure() -> bool {
+    let cgroup = match fs::read_to_string("/proc/self/cgroup") {
+        Ok(cgroup) => cgroup,
+        Err(e) => {
+            tracing::warn!(error = ?e, "failed to find the unit the daemon runs as");
+            return false;
+        }
+    };
+    let Some((unit, user)) = unit_of(&cgroup) else {
+        return false;
+    };
+    let output = Command::new("systemctl")
+        .args(user.then_some("--user"))
+        .args(["show", "--property=Restart", "--value", unit])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => matches!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            "on-failure" | "always"
+        ),
+        Ok(output) => {
+            tracing::warn!(unit, ?output, "systemctl couldn't show the restart policy");
+            false
+        }
+        Err(e) => {
+            tracing::warn!(unit, error = ?e, "failed to run systemctl");
+            false
+        }
+    }
+}
+
+/// The service the process with this `/proc/self/cgroup` belongs to, and whether it's managed
+/// by a user's service manager.
+fn unit_of(cgroup: &str) -> Option<(&str, bool)> {
+    // cgroup v2 has a single hierarchy, numbered 0
+    let path = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let unit = path
+        .rsplit('/')
+        .find(|component| component.ends_with(".service"))?;
+    let user = path
+        .split('/')
+        .any(|component| component.starts_with("user@"));
+    Some((unit, user))
+}
+
+/// Sends `state` to the service manager, if it asked for notifications.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(path.as_encoded_bytes(), state) {
+        tracing::warn!(error = ?e, state, "failed to notify systemd");
+    }
+}
+
+fn send(path: &[u8], state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix(b"@") {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        None => {
+            use std::os::unix::ffi::OsStrExt;
+            socket.send_to(state.as_bytes(), std::ffi::OsStr::from_bytes(path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Tells systemd the config is being reloaded, it has to be followed by `READY=1` once it's
+/// done.
+pub fn reloading() {
+    match clock_gettime(ClockId::CLOCK_MONOTONIC) {
+        Ok(now) => notify(&format!(
+            "RELOADING=1\nMONOTONIC_USEC={}",
+            Duration::from(now).as_micros()
+        )),
+        Err(e) => tracing::warn!(error = ?e, "failed to read the monotonic clock"),
+    }
+}
+
+/// How often systemd expects to hear from the daemon, if the unit has a `WatchdogSec`.
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID")
+        && pid.to_str() != Some(&std::process::id().to_string())
+    {
+        return None;
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+/// Pings the watchdog twice per interval, forever. Never resolves if there's no watchdog.
+pub async fn watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return std::future::pending().await;
+    };
+    tracing::info!(?interval, "pinging the systemd watchdog");
+    let mut ticks = tokio::time::interval(interval / 2);
+    loop {
+        ticks.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn states_reach_the_notify_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let manager = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str().as_encoded_bytes(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn units_are_found_in_the_cgroup() {
+        assert_eq!(
+            unit_of("0::/system.slice/spark.service\n"),
+            Some(("spark.service", false))
+        );
+        assert_eq!(
+            unit_of("0::/user.slice/user-1000.slice/user@1000.service/app.slice/spark.service\n"),
+            Some(("spark.service", true))
+        );
+        assert_eq!(
+            unit_of("0::/user.slice/user-1000.slice/session-2.scope\n"),
+            None
+        );
+    }
+}
@@ -1,6 +1,7 @@
 mod backend;
 mod config;
 mod daemon;
+mod metrics;
 mod routing;
 mod util;
 
@@ -15,7 +16,11 @@ use common::{
     domain::Hostname,
     telemetry::{get_subscriber_no_bunny, init_subscriber},
 };
-use spark_protocol::{Command, ResponseExt};
+use futures::{StreamExt, TryStreamExt};
+use spark_protocol::{
+    Command, ResponseExt,
+    relay::{Broadcast, BroadcastResponse, Targets},
+};
 
 /// A spark to travel the blind eternities!
 #[derive(Parser, Debug)]
@@ -33,13 +38,31 @@ struct Args {
 #[derive(Subcommand, Debug)]
 enum Cmd {
     /// run as a daemon
-    Daemon,
+    Daemon {
+        /// apply changes to the config file without reloading, where possible
+        #[arg(long)]
+        watch_config: bool,
+    },
+    /// how the local daemon is doing
+    Status,
     /// msg
     Msg {
-        #[arg(long)]
+        #[arg(long, group = "target")]
         hostname: Option<Hostname>,
+        /// send to every connected machine
+        #[arg(long, group = "target")]
+        all: bool,
+        /// send to these machines, hostnames can be globs like 'pi*'
+        #[arg(long, value_delimiter = ',', group = "target")]
+        hosts: Vec<String>,
+        /// send to the machines tagged with all of these tags
+        #[arg(long, value_delimiter = ',', group = "target")]
+        tags: Vec<String>,
+        /// how long to wait for each machine when sending to many
+        #[arg(long, value_parser = humantime::parse_duration)]
+        timeout: Option<Duration>,
         #[command(subcommand)]
-        msg: Command,
+        msg: Msg,
     },
     #[command(flatten)]
     SshInline(SshToolInline),
@@ -63,6 +86,25 @@ enum Cmd {
     AutoComplete { shell: clap_complete::Shell },
 }
 
+#[derive(Subcommand, Debug)]
+enum Msg {
+    #[command(flatten)]
+    Command(Command),
+    /// copy a local file to the remote machine
+    Put {
+        local: PathBuf,
+        /// relative paths are relative to the remote user's home directory
+        remote: PathBuf,
+    },
+    /// copy a file from the remote machine
+    Get {
+        /// relative paths are relative to the remote user's home directory
+        remote: PathBuf,
+        /// defaults to the remote file's name, in the current directory
+        local: Option<PathBuf>,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 enum SshToolInline {
     Ssh(routing::SshCommandOpts),
@@ -75,6 +117,12 @@ enum SshTool {
     Rsync(routing::RsyncOpts),
     CopyId(routing::SshOpts),
     Show(routing::ShowRouteOpts),
+    /// print an ssh_config with a host for every known machine, to reach them without spark
+    SshConfig,
+    /// forward a local port to a port on a remote machine
+    Forward(routing::ForwardOpts),
+    /// open a SOCKS proxy that exits through a remote machine
+    Socks(routing::SocksOpts),
 }
 
 #[derive(Subcommand, Debug)]
@@ -118,18 +166,40 @@ enum Backend {
         strict: bool,
     },
     SyncPlaylists,
+    /// publish a spark build that sparks of the same target can update to
+    UploadBuild {
+        /// The target triple the binary was built for
+        #[arg(short, long)]
+        target: String,
+        /// The version of the build
+        #[arg(short, long)]
+        version: String,
+        /// Path to the binary
+        path: PathBuf,
+    },
 }
 
 async fn app(args: Args) -> anyhow::Result<ExitStatus> {
     tracing::debug!("loading configuration");
+    // before anything an update could break, so a broken one is rolled back
+    if let Cmd::Daemon { .. } = args.cmd
+        && let Err(e) = daemon::check_pending()
+    {
+        tracing::error!(?e, "failed to check for a pending update");
+    }
+    let config_path = args.config.clone();
     let config = config::load_configuration(args.config).context("loading configuration")?;
 
     tracing::debug!(?args.cmd);
 
     match args.cmd {
-        Cmd::Daemon => daemon::run_all(config)
-            .await
-            .map(|_| ExitStatus::from_raw(1)),
+        Cmd::Daemon { watch_config } => daemon::run_all(
+            config,
+            move || config::load_configuration(config_path.clone()),
+            watch_config,
+        )
+        .await
+        .map(|exit| ExitStatus::from_raw(exit.code() << 8)),
         Cmd::Route(SshTool::Ssh(opts)) | Cmd::SshInline(SshToolInline::Ssh(opts)) => {
             routing::ssh(&opts, &config).await
         }
@@ -140,7 +210,99 @@ async fn app(args: Args) -> anyhow::Result<ExitStatus> {
             .await
             .map(|_| ExitStatus::from_raw(0)),
         Cmd::Route(SshTool::CopyId(opts)) => routing::copy_id(&opts, &config).await,
-        Cmd::Msg { hostname, msg } => {
+        Cmd::Route(SshTool::Forward(opts)) => routing::forward(&opts, &config).await,
+        Cmd::Route(SshTool::Socks(opts)) => routing::socks(&opts, &config).await,
+        Cmd::Route(SshTool::SshConfig) => routing::ssh_config(&config)
+            .await
+            .map(|_| ExitStatus::from_raw(0)),
+        Cmd::Msg {
+            all,
+            hosts,
+            tags,
+            timeout,
+            msg,
+            ..
+        } if all || !hosts.is_empty() || !tags.is_empty() => {
+            let command = match msg {
+                Msg::Command(Command::Subscribe(_)) => {
+                    anyhow::bail!("subscriptions can only be made to one machine")
+                }
+                Msg::Command(command) => command,
+                Msg::Put { .. } | Msg::Get { .. } => {
+                    anyhow::bail!("files can only be copied to and from one machine")
+                }
+            };
+            let targets = if all {
+                Targets::All
+            } else if !hosts.is_empty() {
+                Targets::Hosts(hosts)
+            } else {
+                Targets::Tags(tags.into_iter().collect())
+            };
+            let responses = daemon::persistent_conn::broadcast(
+                config,
+                Broadcast {
+                    targets,
+                    command,
+                    timeout,
+                },
+            )
+            .await?;
+            show_broadcast(responses);
+            Ok(ExitStatus::from_raw(0))
+        }
+        Cmd::Msg {
+            hostname,
+            msg: Msg::Command(Command::Subscribe(subscription)),
+            ..
+        } => {
+            let mut events = match hostname {
+                None => daemon::ipc::subscribe(subscription, config).await?.boxed(),
+                Some(hostname) => {
+                    daemon::persistent_conn::subscribe(config, hostname, subscription)
+                        .await?
+                        .boxed()
+                }
+            };
+            while let Some(event) = events.try_next().await? {
+                show_event(event);
+            }
+            Ok(ExitStatus::from_raw(0))
+        }
+        Cmd::Msg {
+            hostname,
+            msg: Msg::Put { local, remote },
+            ..
+        } => {
+            let hostname = hostname.context("copying files needs a --hostname")?;
+            let response =
+                daemon::persistent_conn::put_file(config, hostname, &local, remote).await?;
+            show_response(response);
+            Ok(ExitStatus::from_raw(0))
+        }
+        Cmd::Msg {
+            hostname,
+            msg: Msg::Get { remote, local },
+            ..
+        } => {
+            let hostname = hostname.context("copying files needs a --hostname")?;
+            let local = match local {
+                Some(local) => local,
+                None => remote
+                    .file_name()
+                    .with_context(|| format!("{} is not a file path", remote.display()))?
+                    .into(),
+            };
+            let response =
+                daemon::persistent_conn::get_file(config, hostname, remote, &local).await?;
+            show_response(response);
+            Ok(ExitStatus::from_raw(0))
+        }
+        Cmd::Msg {
+            hostname,
+            msg: Msg::Command(msg),
+            ..
+        } => {
             let response = match hostname {
                 None => daemon::ipc::send(&msg, config).await?,
                 Some(hostname) => daemon::persistent_conn::send(config, hostname, msg).await?,
@@ -162,6 +324,13 @@ async fn app(args: Args) -> anyhow::Result<ExitStatus> {
             show_response(response);
             Ok(ExitStatus::from_raw(0))
         }
+        Cmd::Status => {
+            let response = daemon::ipc::send(&Command::Status, config)
+                .await
+                .context("is the daemon running?")?;
+            show_response(response);
+            Ok(ExitStatus::from_raw(0))
+        }
         Cmd::Backend(cmd) => backend::handle(cmd, config)
             .await
             .map(|_| ExitStatus::from_raw(0)),
@@ -185,6 +354,47 @@ fn show_response(response: spark_protocol::Response) {
     }
 }
 
+/// One row per machine, or a json object keyed by hostname when the output isn't a terminal.
+fn show_broadcast(responses: BroadcastResponse) {
+    if !std::io::stdout().is_terminal() {
+        serde_json::to_writer(std::io::stdout().lock(), &responses).unwrap();
+        return;
+    }
+    if responses.is_empty() {
+        println!("no machines matched");
+        return;
+    }
+    let width = responses
+        .keys()
+        .map(|hostname| hostname.as_ref().len())
+        .max()
+        .unwrap_or_default()
+        .max("HOST".len());
+    println!("{:width$}  RESPONSE", "HOST");
+    for (hostname, response) in &responses {
+        let response = response.display().to_string();
+        let mut lines = response.lines();
+        println!(
+            "{:width$}  {}",
+            hostname.as_ref(),
+            lines.next().unwrap_or_default()
+        );
+        for line in lines {
+            println!("{:width$}  {line}", "");
+        }
+    }
+}
+
+/// Like [`show_response`] but one event per line, so the output can be consumed while it's being
+/// streamed.
+fn show_event(event: spark_protocol::Response) {
+    if std::io::stdout().is_terminal() {
+        println!("{}", event.display());
+    } else {
+        println!("{}", serde_json::to_string(&event).unwrap())
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
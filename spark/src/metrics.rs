@@ -0,0 +1,26 @@
+use common::make_metric;
+use prometheus_client::metrics;
+
+make_metric!(
+    "number of times the persistent connection came back up",
+    persistent_conn_reconnects(metrics::counter::Counter),
+    {}
+);
+
+make_metric!(
+    "number of commands handled, from the backend or the ipc socket",
+    commands_handled(metrics::counter::Counter),
+    { kind: &'static str }
+);
+
+make_metric!(
+    "seconds spent handling commands, over commands_handled it's the average latency",
+    command_seconds(metrics::counter::Counter<f64>),
+    { kind: &'static str }
+);
+
+make_metric!(
+    "number of machine status posts that failed",
+    heartbeat_failures(metrics::counter::Counter),
+    {}
+);
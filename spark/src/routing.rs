@@ -1,5 +1,7 @@
 use std::{
     collections::HashMap,
+    fmt::{self, Write},
+    future::Future,
     iter,
     mem::replace,
     net::{IpAddr, Ipv4Addr},
@@ -11,16 +13,16 @@ use std::{
 
 use anyhow::Context;
 use arrayvec::ArrayVec;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use clap::{ArgAction, Parser};
 use common::{
-    algorithms::net_graph::{NetGraph, SimpleNode},
+    algorithms::net_graph::{NetGraph, SimpleNode, Staleness},
     domain::{Hostname, machine_status::MachineStatusFull},
     net::AuthenticatedClient,
 };
 use itertools::Itertools;
 use tokio::{fs::File, process::Command};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use crate::{
     config::Config,
@@ -40,10 +42,16 @@ pub(super) struct SshOpts {
     dry_run: bool,
 }
 
+/// Routes are tried until one of them connects, ssh exits with 255 when it couldn't.
+const CONNECTION_FAILED: i32 = 255;
+
 #[derive(Parser, Debug)]
 pub(super) struct SshCommandOpts {
     #[command(flatten)]
     core: SshOpts,
+    /// how many routes to try when the connection fails
+    #[arg(long, default_value_t = 3)]
+    max_attempts: usize,
     #[arg(short = 'T')]
     no_pseudo_terminal: bool,
     #[arg(short = 'c', long = "shell", conflicts_with("args"))]
@@ -52,7 +60,7 @@ pub(super) struct SshCommandOpts {
 }
 
 pub(super) async fn ssh(opts: &SshCommandOpts, config: &Config) -> anyhow::Result<ExitStatus> {
-    let args = route_to_ssh_hops(
+    let routes = routes_to_ssh_hops(
         &opts.core.destination,
         config,
         if opts.no_pseudo_terminal {
@@ -60,29 +68,61 @@ pub(super) async fn ssh(opts: &SshCommandOpts, config: &Config) -> anyhow::Resul
         } else {
             PseudoTty::Allocate
         },
+        opts.max_attempts,
     )
     .await
     .context("getting ssh hops")?;
-    let (ssh, args) = args
-        .split_first()
-        .expect("There should be at least one string here 🤔");
-
-    let mut cmd = std::process::Command::new(ssh);
-    cmd.args(args);
-    match &opts.sub_shell {
-        Some(script) => cmd.args(["bash", "-c", script]),
-        None => cmd.arg("--").args(&opts.args),
-    };
-    debug!("running ssh with args [{:?}]", cmd.get_args().format(", "));
-    if opts.core.dry_run {
-        Ok(ExitStatus::from_raw(0))
-    } else {
-        Ok(Command::from(cmd)
-            .spawn()?
-            .wait()
-            .await
-            .context("waiting for the ssh command")?)
+    with_fallback(&routes, |args| {
+        let (ssh, args) = args
+            .split_first()
+            .expect("There should be at least one string here 🤔");
+
+        let mut cmd = std::process::Command::new(ssh);
+        cmd.args(args);
+        match &opts.sub_shell {
+            Some(script) => cmd.args(["bash", "-c", script]),
+            None => cmd.arg("--").args(&opts.args),
+        };
+        debug!("running ssh with args [{:?}]", cmd.get_args().format(", "));
+        async move {
+            if opts.core.dry_run {
+                Ok(ExitStatus::from_raw(0))
+            } else {
+                Command::from(cmd)
+                    .spawn()?
+                    .wait()
+                    .await
+                    .context("waiting for the ssh command")
+            }
+        }
+    })
+    .await
+}
+
+/// Runs `run` with each route in turn, for as long as the connection fails.
+async fn with_fallback<F, Fut>(routes: &[Route], mut run: F) -> anyhow::Result<ExitStatus>
+where
+    F: FnMut(&[String]) -> Fut,
+    Fut: Future<Output = anyhow::Result<ExitStatus>>,
+{
+    for (attempt, route) in routes.iter().enumerate() {
+        let status = run(&route.args).await?;
+        if status.code() != Some(CONNECTION_FAILED) {
+            info!(
+                attempt = attempt + 1,
+                "connected through {}", route.description
+            );
+            return Ok(status);
+        }
+        match routes.get(attempt + 1) {
+            Some(next) => warn!(
+                "connecting through {} failed, trying {}",
+                route.description, next.description
+            ),
+            None => warn!("connecting through {} failed", route.description),
+        }
     }
+    Ok(ExitStatus::from_raw(CONNECTION_FAILED << 8))
 }
 
 #[derive(Parser, Debug)]
@@ -90,6 +130,9 @@ pub(super) struct RsyncOpts {
     rsync_options: String,
     #[arg(long = "dry-run")]
     dry_run: bool,
+    /// how many routes to try when the connection fails
+    #[arg(long, default_value_t = 3)]
+    max_attempts: usize,
     paths: Vec<String>,
 }
 
@@ -105,38 +148,70 @@ fn get_host<S: AsRef<str>>(
 pub(super) async fn rsync(opts: &RsyncOpts, config: &Config) -> anyhow::Result<ExitStatus> {
     let host =
         get_host(&opts.paths).ok_or_else(|| anyhow::anyhow!("not remote host specified"))??;
-    #[allow(unstable_name_collisions)]
-    let bridge = route_to_ssh_hops(&host, config, PseudoTty::None)
-        .await?
-        .iter()
-        .map(|s| s.as_str())
-        .intersperse(" ")
-        .collect::<String>();
-    let mut cmd = std::process::Command::new("rsync");
-    cmd.arg(format!(
-        "-{}{}",
-        opts.rsync_options,
-        if opts.dry_run { "n" } else { "" }
-    ));
-    cmd.args(["-e", &bridge]);
-    for f in &opts.paths {
-        match f.split_once(':') {
-            Some((_, path)) => cmd.arg(format!(":{path}")),
-            None => cmd.arg(f),
-        };
+    let routes = routes_to_ssh_hops(&host, config, PseudoTty::None, opts.max_attempts).await?;
+    with_fallback(&routes, |args| {
+        #[allow(unstable_name_collisions)]
+        let bridge = args
+            .iter()
+            .map(|s| s.as_str())
+            .intersperse(" ")
+            .collect::<String>();
+        let mut cmd = std::process::Command::new("rsync");
+        cmd.arg(format!(
+            "-{}{}",
+            opts.rsync_options,
+            if opts.dry_run { "n" } else { "" }
+        ));
+        cmd.args(["-e", &bridge]);
+        for f in &opts.paths {
+            match f.split_once(':') {
+                Some((_, path)) => cmd.arg(format!(":{path}")),
+                None => cmd.arg(f),
+            };
+        }
+        debug!(
+            "running rsync with args: [{:?}]",
+            cmd.get_args().format(", ")
+        );
+        async move {
+            info!("------- running rsync -------");
+            let r = Command::from(cmd)
+                .spawn()?
+                .wait()
+                .await
+                .context("waiting for rsync");
+            info!("-----------------------------");
+            r
+        }
+    })
+    .await
+}
+
+/// How long ago a machine last posted its status, flagged if that makes it stale.
+struct LastSeen {
+    ago: std::time::Duration,
+    stale: bool,
+}
+
+impl LastSeen {
+    fn of(status: &MachineStatusFull, staleness: Staleness, now: DateTime<Utc>) -> Self {
+        let ago = (now - status.last_heartbeat).to_std().unwrap_or_default();
+        Self {
+            // whole seconds, the rest is noise
+            ago: std::time::Duration::from_secs(ago.as_secs()),
+            stale: staleness.stale_for(status, now).is_some(),
+        }
+    }
+}
+
+impl fmt::Display for LastSeen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(seen {} ago", humantime::format_duration(self.ago))?;
+        if self.stale {
+            f.write_str(", stale")?;
+        }
+        f.write_str(")")
     }
-    debug!(
-        "running rsync with args: [{:?}]",
-        cmd.get_args().format(", ")
-    );
-    info!("------- running rsync -------");
-    let r = Ok(Command::from(cmd)
-        .spawn()?
-        .wait()
-        .await
-        .context("waiting for rsync")?);
-    info!("-----------------------------");
-    r
 }
 
 #[derive(Debug, Parser)]
@@ -145,6 +220,8 @@ pub struct ShowRouteOpts {
     filename: Option<PathBuf>,
     #[arg(short, long)]
     destination: Option<Hostname>,
+    /// list the known machines and how long ago they were last heard from instead, repeat for
+    /// their addresses
     #[arg(short, long, action = ArgAction::Count)]
     list: u8,
 }
@@ -153,12 +230,12 @@ pub(super) async fn show_route(opts: &ShowRouteOpts, config: &Config) -> anyhow:
     let (statuses, hostname) = fetch_statuses(config).await?;
 
     'list_hostnames: {
-        let printer: fn((String, MachineStatusFull)) = match opts.list {
+        let printer: fn(String, MachineStatusFull, LastSeen) = match opts.list {
             0 => break 'list_hostnames,
-            1 => |(name, _)| println!("{name}"),
-            2 => |(name, status)| {
+            1 => |name, _, seen| println!("{name:<15} {seen}"),
+            2 => |name, status, seen| {
                 println!(
-                    "{name:<15} @ [{:<15}] -> {}",
+                    "{name:<15} @ [{:<15}] -> {:<15} {seen}",
                     status.external_ip,
                     status
                         .preferred_ip()
@@ -166,19 +243,23 @@ pub(super) async fn show_route(opts: &ShowRouteOpts, config: &Config) -> anyhow:
                         .unwrap_or_else(|| "unknown".to_string())
                 )
             },
-            3.. => |(name, MachineStatusFull { fields: status, .. })| {
+            3.. => |name, MachineStatusFull { fields: status, .. }, seen| {
                 print!("{name:<15} @ [{:^15}] -> ", status.external_ip);
                 for ip in status.ip_connections.into_iter().map(|c| c.local_ip) {
                     print!("{ip} ")
                 }
-                println!();
+                println!("{seen}");
             },
         };
-        statuses.into_iter().for_each(printer);
+        let now = Utc::now();
+        for (name, status) in statuses {
+            let seen = LastSeen::of(&status, config.network.staleness, now);
+            printer(name, status, seen);
+        }
         return Ok(());
     }
 
-    let graph = build_net_graph(&statuses);
+    let graph = build_net_graph(&statuses, config);
 
     let path = match opts.destination.as_ref() {
         Some(d) => graph.find_path(&hostname, d),
@@ -228,6 +309,193 @@ pub(super) async fn show_route(opts: &ShowRouteOpts, config: &Config) -> anyhow:
     Ok(())
 }
 
+/// Prints an ssh_config fragment with a `Host` for every known machine and alias, reached the
+/// same way `spark ssh` would reach them.
+pub(super) async fn ssh_config(config: &Config) -> anyhow::Result<()> {
+    let (statuses, hostname) = fetch_statuses(config).await?;
+    let graph = build_net_graph(&statuses, config);
+    let route_to = |target: &Hostname| {
+        graph
+            .find_path(&hostname, target)
+            .and_then(|p| graph.path_to_ips(&p))
+            .map(|mut path| {
+                // the first node is this machine
+                if path.len() > 1 {
+                    path.remove(0);
+                }
+                path
+            })
+    };
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# generated by `spark route ssh-config` from {hostname}"
+    )?;
+    for (_, status) in statuses.iter().sorted_by_key(|(name, _)| *name) {
+        let target = &status.hostname;
+        if *target == hostname {
+            continue;
+        }
+        match route_to(target) {
+            Some(path) => host_block(&mut out, target.as_ref(), &path, None)?,
+            None => writeln!(out, "\n# {target}: no route found")?,
+        }
+    }
+    for (alias, destination) in config.network.aliases.iter().sorted_by_key(|(a, _)| *a) {
+        match route_to(&destination.hostname) {
+            Some(path) => host_block(&mut out, alias, &path, destination.username.as_deref())?,
+            None => writeln!(out, "\n# {alias}: no route found to {destination}")?,
+        }
+    }
+    print!("{out}");
+    Ok(())
+}
+
+/// One `Host` entry, jumping through every node of `path` but the last. The user is picked like
+/// [`path_to_args`] does, except that ssh is left to pick it when neither is known.
+fn host_block(
+    out: &mut String,
+    name: &str,
+    path: &[SimpleNode],
+    username: Option<&str>,
+) -> fmt::Result {
+    let Some((target, jumps)) = path.split_last() else {
+        return Ok(());
+    };
+    writeln!(out, "\nHost {name}")?;
+    writeln!(out, "    HostName {}", target.ip)?;
+    writeln!(out, "    Port {}", target.port)?;
+    if let Some(user) = target.default_username.as_deref().or(username) {
+        writeln!(out, "    User {user}")?;
+    }
+    if !jumps.is_empty() {
+        writeln!(out, "    ProxyJump {}", proxy_jump(jumps, username))?;
+    }
+    Ok(())
+}
+
+/// The hops in the `[user@]host[:port],...` form of ssh's `-J` and `ProxyJump`.
+fn proxy_jump<'a>(jumps: &'a [SimpleNode], username: Option<&'a str>) -> impl fmt::Display + 'a {
+    jumps.iter().format_with(",", move |node, f| {
+        if let Some(user) = node.default_username.as_deref().or(username) {
+            f(&format_args!("{user}@"))?;
+        }
+        match node.ip {
+            IpAddr::V4(ip) => f(&format_args!("{ip}:{}", node.port)),
+            IpAddr::V6(ip) => f(&format_args!("[{ip}]:{}", node.port)),
+        }
+    })
+}
+
+/// A port on a remote machine, like `pi:8080`.
+#[derive(Debug, Clone)]
+pub(super) struct RemotePort {
+    destination: Destination,
+    port: u16,
+}
+
+impl FromStr for RemotePort {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (destination, port) = s
+            .rsplit_once(':')
+            .with_context(|| format!("expected <host>:<port>, got {s:?}"))?;
+        Ok(Self {
+            destination: destination.parse()?,
+            port: port.parse().context("parsing the port")?,
+        })
+    }
+}
+
+#[derive(Parser, Debug)]
+pub(super) struct ForwardOpts {
+    remote: RemotePort,
+    /// defaults to the remote port
+    local_port: Option<u16>,
+    #[arg(long = "dry-run")]
+    dry_run: bool,
+}
+
+#[derive(Parser, Debug)]
+pub(super) struct SocksOpts {
+    #[command(flatten)]
+    core: SshOpts,
+    /// the local port the proxy listens on
+    #[arg(short, long, default_value_t = 1080)]
+    port: u16,
+}
+
+pub(super) async fn forward(opts: &ForwardOpts, config: &Config) -> anyhow::Result<ExitStatus> {
+    let RemotePort { destination, port } = &opts.remote;
+    let local_port = opts.local_port.unwrap_or(*port);
+    tunnel(
+        destination,
+        config,
+        ["-L".into(), format!("{local_port}:localhost:{port}")],
+        opts.dry_run,
+    )
+    .await
+}
+
+pub(super) async fn socks(opts: &SocksOpts, config: &Config) -> anyhow::Result<ExitStatus> {
+    tunnel(
+        &opts.core.destination,
+        config,
+        ["-D".into(), opts.port.to_string()],
+        opts.core.dry_run,
+    )
+    .await
+}
+
+/// Holds an ssh connection to `destination` open for the sake of `forward`, until it's
+/// interrupted.
+async fn tunnel(
+    destination: &Destination,
+    config: &Config,
+    forward: [String; 2],
+    dry_run: bool,
+) -> anyhow::Result<ExitStatus> {
+    let (username, hostname) = destination.resolve_alias(&config.network.aliases);
+    let path = find_path(destination, config, hostname).await?;
+    let mut cmd = std::process::Command::new("ssh");
+    cmd.args(tunnel_args(&path, &username, forward));
+    info!(
+        "running ssh {}",
+        cmd.get_args().map(|a| a.to_string_lossy()).format(" ")
+    );
+    if dry_run {
+        Ok(ExitStatus::from_raw(0))
+    } else {
+        Ok(Command::from(cmd)
+            .spawn()?
+            .wait()
+            .await
+            .context("waiting for the ssh command")?)
+    }
+}
+
+/// Unlike [`path_to_args`], the hops are jumped through with `-J` so that the forward ends up
+/// on the last machine instead of the first.
+fn tunnel_args(path: &[SimpleNode], username: &str, forward: [String; 2]) -> Vec<String> {
+    let (target, jumps) = path.split_last().expect("paths are never empty");
+    let mut args = vec!["-N".to_string()];
+    args.extend(forward);
+    if !jumps.is_empty() {
+        args.extend(["-J".into(), proxy_jump(jumps, Some(username)).to_string()]);
+    }
+    args.extend([
+        "-p".into(),
+        target.port.to_string(),
+        format!(
+            "{}@{}",
+            target.default_username.as_deref().unwrap_or(username),
+            target.ip
+        ),
+    ]);
+    args
+}
+
 pub(crate) async fn copy_id(opts: &SshOpts, config: &Config) -> anyhow::Result<ExitStatus> {
     let (username, hostname) = opts.destination.resolve_alias(&config.network.aliases);
 
@@ -275,48 +543,78 @@ async fn find_path(
     config: &Config,
     dest_hostname: &Hostname,
 ) -> anyhow::Result<Vec<SimpleNode>> {
+    let mut paths = find_paths(destination, config, dest_hostname, 1).await?;
+    Ok(paths.remove(0))
+}
+
+/// Up to `k` distinct paths to `dest_hostname`, best first. Never empty.
+async fn find_paths(
+    destination: &Destination,
+    config: &Config,
+    dest_hostname: &Hostname,
+    k: usize,
+) -> anyhow::Result<Vec<Vec<SimpleNode>>> {
     let (statuses, hostname) = fetch_statuses(config).await?;
-    // TODO: there might be stale statuses here
     if statuses.is_empty() {
         debug!("there are no statuses");
     }
 
-    let graph = build_net_graph(&statuses);
+    let graph = build_net_graph(&statuses, config);
 
-    let path = match graph
-        .find_path(&hostname, dest_hostname)
-        .and_then(|p| graph.path_to_ips(&p))
-    {
-        Some(mut path) => {
+    let paths = graph
+        .find_paths(&hostname, dest_hostname, k)
+        .iter()
+        .filter_map(|p| graph.path_to_ips(p))
+        .map(|mut path| {
             // if we have more than one target we can skip localhost
             if path.len() > 1 {
                 path.remove(0);
             }
             path
-        }
-        None => {
-            return Err(anyhow::anyhow!(
-                "Path could not be found to '{destination}'",
-            ));
-        }
-    };
+        })
+        // different paths through the graph can take the same hops
+        .unique()
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Path could not be found to '{destination}'",
+        ));
+    }
 
-    debug!(?path, "found a path");
+    debug!(?paths, "found paths");
 
-    Ok(path)
+    Ok(paths)
 }
 
-async fn route_to_ssh_hops(
+/// The ssh command for one path, and how to tell it apart from the others in the logs.
+struct Route {
+    args: Vec<String>,
+    description: String,
+}
+
+async fn routes_to_ssh_hops(
     destination: &Destination,
     config: &Config,
     pseudo_tty: PseudoTty,
-) -> anyhow::Result<Vec<String>> {
+    max_attempts: usize,
+) -> anyhow::Result<Vec<Route>> {
     let (username, hostname) = destination.resolve_alias(&config.network.aliases);
 
-    let path = find_path(destination, config, hostname).await?;
+    let paths = find_paths(destination, config, hostname, max_attempts.max(1)).await?;
 
-    Ok(path_to_args(&path, &username, pseudo_tty)
-        .flatten()
+    Ok(paths
+        .iter()
+        .map(|path| Route {
+            args: path_to_args(path, &username, pseudo_tty)
+                .flatten()
+                .collect(),
+            description: path
+                .iter()
+                .format_with(" -> ", |node, f| {
+                    f(&format_args!("{}:{}", node.ip, node.port))
+                })
+                .to_string(),
+        })
         .collect())
 }
 
@@ -349,10 +647,22 @@ async fn fetch_statuses(
         last_heartbeat: Utc::now(),
     };
 
+    let hostname = with_local_status(&mut statuses, this);
+    Ok((statuses, hostname))
+}
+
+/// Puts the status of this machine in `statuses`, over what the backend had. Peers are only
+/// probed by the heartbeat, so the ones the backend knows about are kept.
+fn with_local_status(
+    statuses: &mut HashMap<String, MachineStatusFull>,
+    mut this: MachineStatusFull,
+) -> Hostname {
+    if let Some(reported) = statuses.remove(this.hostname.as_ref()) {
+        this.fields.peers = reported.fields.peers;
+    }
     let hostname = this.hostname.clone();
     statuses.insert(this.hostname.to_string(), this);
-
-    Ok((statuses, hostname))
+    hostname
 }
 
 struct SshCommand<'u> {
@@ -407,7 +717,7 @@ fn path_to_args<'a>(
     username: &'a str,
     pseudo_tty: PseudoTty,
 ) -> impl Iterator<Item = SshCommand<'a>> {
-    info!(
+    debug!(
         "{}",
         iter::once((Some(username), IpAddr::V4(Ipv4Addr::LOCALHOST), 22))
             .chain(
@@ -435,18 +745,23 @@ fn path_to_args<'a>(
     )
 }
 
-fn build_net_graph(statuses: &HashMap<String, MachineStatusFull>) -> NetGraph<'_> {
-    NetGraph::from_iter(
+fn build_net_graph<'s>(
+    statuses: &'s HashMap<String, MachineStatusFull>,
+    config: &Config,
+) -> NetGraph<'s> {
+    NetGraph::with_staleness(
         statuses
             .iter()
             .inspect(|(n, _)| debug!("found machine: '{}'", n))
             .map(|(_, m)| m),
+        config.network.staleness,
     )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use common::domain::machine_status::{IpConnection, MachineStatus, PeerProbe};
     use std::{
         iter::repeat_n,
         net::{IpAddr, Ipv4Addr},
@@ -608,4 +923,151 @@ mod tests {
             expect
         );
     }
+
+    #[test]
+    fn ssh_config_jumps_through_all_but_the_last_node() {
+        let path = vec![
+            SimpleNode {
+                default_username: Some("mendess".into()),
+                ip: IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                port: 2222,
+            },
+            SimpleNode {
+                default_username: None,
+                ip: "fe80::1".parse().unwrap(),
+                port: 22,
+            },
+            SimpleNode {
+                default_username: None,
+                ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)),
+                port: 22,
+            },
+        ];
+        let mut out = String::new();
+        host_block(&mut out, "pi", &path, Some("pi")).unwrap();
+        assert_eq!(
+            out,
+            "
+Host pi
+    HostName 192.168.1.2
+    Port 22
+    User pi
+    ProxyJump mendess@1.2.3.4:2222,pi@[fe80::1]:22
+"
+        );
+    }
+
+    #[test]
+    fn ssh_config_without_jumps_or_user() {
+        let path = vec![SimpleNode {
+            default_username: None,
+            ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
+            port: 22,
+        }];
+        let mut out = String::new();
+        host_block(&mut out, "tolaria", &path, None).unwrap();
+        assert_eq!(
+            out,
+            "\nHost tolaria\n    HostName 192.168.1.1\n    Port 22\n"
+        );
+    }
+
+    #[test]
+    fn forwards_end_on_the_last_machine() {
+        let path = vec![
+            SimpleNode {
+                default_username: Some("mendess".into()),
+                ip: IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                port: 2222,
+            },
+            SimpleNode {
+                default_username: None,
+                ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)),
+                port: 22,
+            },
+        ];
+        assert_eq!(
+            tunnel_args(&path, "pi", ["-L".into(), "8080:localhost:80".into()]),
+            [
+                "-N",
+                "-L",
+                "8080:localhost:80",
+                "-J",
+                "mendess@1.2.3.4:2222",
+                "-p",
+                "22",
+                "pi@192.168.1.2"
+            ]
+        );
+    }
+
+    #[test]
+    fn remote_ports_parse() {
+        let remote = "pi@tolaria:8080".parse::<RemotePort>().unwrap();
+        assert_eq!(remote.destination.to_string(), "pi@tolaria");
+        assert_eq!(remote.port, 8080);
+        assert!("tolaria".parse::<RemotePort>().is_err());
+    }
+
+    #[tokio::test]
+    async fn routes_are_tried_until_one_connects() {
+        let routes = (0..3)
+            .map(|i| Route {
+                args: vec![i.to_string()],
+                description: format!("route {i}"),
+            })
+            .collect::<Vec<_>>();
+        let mut tried = vec![];
+        let status = with_fallback(&routes, |args| {
+            tried.push(args[0].clone());
+            let code = if args[0] == "0" { CONNECTION_FAILED } else { 1 };
+            async move { Ok(ExitStatus::from_raw(code << 8)) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(status.code(), Some(1));
+        assert_eq!(tried, ["0", "1"]);
+    }
+
+    fn lan_status(hostname: &str, peers: Vec<PeerProbe>) -> MachineStatusFull {
+        MachineStatusFull {
+            fields: MachineStatus {
+                hostname: hostname.parse().unwrap(),
+                ip_connections: vec![IpConnection {
+                    local_ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)),
+                    gateway_ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
+                    gateway_mac: None,
+                }],
+                ssh: None,
+                external_ip: IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                default_user: None,
+                peers,
+            },
+            last_heartbeat: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn routes_from_here_skip_peers_the_heartbeat_couldnt_reach() {
+        let unreachable = PeerProbe {
+            hostname: "weatherlight".parse().unwrap(),
+            rtt: None,
+        };
+        let mut statuses = [
+            lan_status("tolaria", vec![unreachable]),
+            lan_status("weatherlight", vec![]),
+        ]
+        .into_iter()
+        .map(|s| (s.hostname.to_string(), s))
+        .collect::<HashMap<_, _>>();
+
+        // the local status doesn't probe its peers
+        let origin = with_local_status(&mut statuses, lan_status("tolaria", vec![]));
+        assert_eq!(statuses["tolaria"].peers.len(), 1);
+        let graph = NetGraph::from_iter(statuses.values());
+        assert_eq!(
+            graph.find_path(&origin, &"weatherlight".parse().unwrap()),
+            None
+        );
+    }
 }
@@ -31,7 +31,7 @@ async fn get_external_ip() -> anyhow::Result<IpAddr> {
 }
 
 #[cfg(target_os = "android")]
-async fn get_ip_connections() -> anyhow::Result<Vec<IpConnection>> {
+pub(crate) async fn get_ip_connections() -> anyhow::Result<Vec<IpConnection>> {
     let output = Command::new("ifconfig").output().await?;
 
     fn extract_ip(interface: &str, data: &str) -> Option<(IpAddr, IpAddr)> {
@@ -87,7 +87,7 @@ async fn get_ip_connections() -> anyhow::Result<Vec<IpConnection>> {
 }
 
 #[cfg(not(target_os = "android"))]
-async fn get_ip_connections() -> anyhow::Result<Vec<IpConnection>> {
+pub(crate) async fn get_ip_connections() -> anyhow::Result<Vec<IpConnection>> {
     let (gateway, ips) = tokio::task::spawn_blocking(default_net::get_interfaces)
         .await
         .context("panicked while getting interfaces")?
@@ -209,5 +209,6 @@ pub(crate) async fn get_current_status(config: &Config) -> anyhow::Result<Machin
             let username = whoami::username();
             (username != "root").then_some(username)
         }),
+        peers: vec![],
     })
 }